@@ -1,3 +1,4 @@
+use crate::resolution::Codec;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::Path;
@@ -6,12 +7,52 @@ use std::sync::Mutex;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AppConfig {
     pub save_path: String,
+    /// 单个流的下载连接数，1 为不分段
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+    /// 队列同时下载的任务数
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// 临时错误（超时、5xx、风控等）的最多重试次数
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// 所有任务合计的限速，KiB/s，0 为不限速
+    #[serde(default)]
+    pub speed_limit: u64,
+    /// 单个任务的限速，KiB/s，0 为不限速
+    #[serde(default)]
+    pub task_speed_limit: u64,
+    /// 视频编码偏好（avc、hevc、av1），按优先级排列，空为不限
+    #[serde(default)]
+    pub codec_preference: Vec<Codec>,
+    /// 没有偏好中的编码时下载失败，而不是退回其他编码
+    #[serde(default)]
+    pub codec_strict: bool,
+}
+
+fn default_connections() -> usize {
+    1
+}
+
+fn default_max_concurrent() -> usize {
+    2
+}
+
+fn default_max_retries() -> u32 {
+    3
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             save_path: "./download".to_string(),
+            connections: default_connections(),
+            max_concurrent: default_max_concurrent(),
+            max_retries: default_max_retries(),
+            speed_limit: 0,
+            task_speed_limit: 0,
+            codec_preference: Vec::new(),
+            codec_strict: false,
         }
     }
 }
@@ -0,0 +1,153 @@
+use crate::resume;
+use anyhow::Result;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// 任务的控制状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlState {
+    Running,
+    Paused,
+    /// keep_files 为 true 时保留临时文件，下次可继续下载
+    Cancelled {
+        keep_files: bool,
+    },
+}
+
+/// 下载被用户取消
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "下载已取消")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// 下载因暂停或取消中断，续传状态已保存
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "下载已中断")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// 单个任务的暂停/继续/取消开关，克隆后共享同一状态
+#[derive(Clone)]
+pub struct TaskControl {
+    tx: Arc<watch::Sender<ControlState>>,
+}
+
+impl TaskControl {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(ControlState::Running);
+        Self { tx: Arc::new(tx) }
+    }
+
+    pub fn state(&self) -> ControlState {
+        *self.tx.borrow()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.state(), ControlState::Cancelled { .. })
+    }
+
+    pub fn pause(&self) {
+        self.tx.send_if_modified(|s| {
+            let changed = *s == ControlState::Running;
+            if changed {
+                *s = ControlState::Paused;
+            }
+            changed
+        });
+    }
+
+    pub fn resume(&self) {
+        self.tx.send_if_modified(|s| {
+            let changed = *s == ControlState::Paused;
+            if changed {
+                *s = ControlState::Running;
+            }
+            changed
+        });
+    }
+
+    pub fn cancel(&self, keep_files: bool) {
+        self.tx.send_replace(ControlState::Cancelled { keep_files });
+    }
+
+    /// 暂停时等待继续，已取消时返回 [`Cancelled`]
+    pub async fn checkpoint(&self) -> Result<()> {
+        let mut rx = self.tx.subscribe();
+        loop {
+            match *rx.borrow_and_update() {
+                ControlState::Running => return Ok(()),
+                ControlState::Cancelled { .. } => return Err(Cancelled.into()),
+                ControlState::Paused => {}
+            }
+            if rx.changed().await.is_err() {
+                return Err(Cancelled.into());
+            }
+        }
+    }
+
+    /// 等待状态离开 Running（暂停或取消）
+    pub async fn interrupted(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|s| *s != ControlState::Running).await;
+    }
+
+    /// 等待任务被取消
+    pub async fn cancelled(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx
+            .wait_for(|s| matches!(s, ControlState::Cancelled { .. }))
+            .await;
+    }
+
+    /// 反复执行下载，暂停中断后等待继续并从续传状态重新开始
+    pub async fn run<F, Fut>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        loop {
+            self.checkpoint().await?;
+            match f().await {
+                Err(e) if e.is::<Interrupted>() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// 取消且不保留文件时，删除下载的临时文件
+    pub fn cleanup(&self, paths: &[&str]) {
+        if self.state() == (ControlState::Cancelled { keep_files: false }) {
+            for path in paths {
+                resume::discard(path);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_pause_resume() {
+    let control = TaskControl::new();
+    control.pause();
+    assert_eq!(control.state(), ControlState::Paused);
+    let waiter = control.clone();
+    let handle = tokio::spawn(async move { waiter.checkpoint().await.is_ok() });
+    control.resume();
+    assert!(handle.await.unwrap());
+    control.cancel(true);
+    assert!(control.checkpoint().await.is_err());
+}
@@ -0,0 +1,332 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{append_history, read_cookie_or_not, remove_punctuation};
+use crate::download::{Downloader, Stream, TaskContext};
+use crate::refresh_cookie::create_headers;
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 歌单每页的歌曲数
+const MENU_PAGE_SIZE: u32 = 100;
+
+/// 音频区的一首歌
+#[derive(Debug, Clone)]
+struct Song {
+    id: i64,
+    title: String,
+    cover: String,
+    /// 歌词文件地址，没有歌词时为空
+    lyric: String,
+}
+
+/// 解析歌曲信息接口或歌单列表中的一项
+fn parse_song(item: &Value) -> Option<Song> {
+    Some(Song {
+        id: item["id"].as_i64()?,
+        title: remove_punctuation(item["title"].as_str().unwrap_or("no title")),
+        cover: item["cover"].as_str().unwrap_or("").to_string(),
+        lyric: item["lyric"].as_str().unwrap_or("").to_string(),
+    })
+}
+
+async fn get_json(
+    client: &Client,
+    url: &str,
+    query: &[(&str, &str)],
+    headers: HeaderMap,
+) -> Result<Value> {
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    Ok(json)
+}
+
+/// 歌曲信息
+async fn get_song(client: &Client, au_id: &str, headers: HeaderMap) -> Result<Song> {
+    let url = "https://www.bilibili.com/audio/music-service-c/web/song/info";
+    let json = get_json(client, url, &[("sid", au_id)], headers).await?;
+    parse_song(&json["data"]).context("Song info does not contain id")
+}
+
+/// 歌曲的下载地址，quality 3 为无损，没有权限时接口会降到可用的最高音质
+async fn get_song_stream(client: &Client, song_id: i64, headers: HeaderMap) -> Result<Stream> {
+    let url = "https://api.bilibili.com/audio/music-service-c/url";
+    let song_id = song_id.to_string();
+    let query = [
+        ("songid", song_id.as_str()),
+        ("quality", "3"),
+        ("privilege", "2"),
+        ("mid", "0"),
+        ("platform", "android"),
+    ];
+    let json = get_json(client, url, &query, headers).await?;
+    parse_stream(&json["data"])
+}
+
+fn parse_stream(data: &Value) -> Result<Stream> {
+    let urls: Vec<String> = data["cdns"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|u| u.as_str())
+        .filter(|u| !u.is_empty())
+        .map(|u| u.to_string())
+        .collect();
+    if urls.is_empty() {
+        return Err(anyhow::anyhow!("No audio url, the song may be unavailable"));
+    }
+    Ok(Stream {
+        urls,
+        path: String::new(),
+        size: data["size"].as_u64(),
+    })
+}
+
+/// 按地址中的扩展名决定保存格式，无损为 flac，其余为 m4a
+fn audio_ext(url: &str) -> &'static str {
+    let path = url.split('?').next().unwrap_or(url);
+    if path.ends_with(".flac") {
+        "flac"
+    } else {
+        "m4a"
+    }
+}
+
+/// 下载一首歌及其封面和歌词，name 为不含扩展名的文件名
+async fn down_song(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    song: &Song,
+    name: &str,
+    save_path: &str,
+) -> Result<()> {
+    let ctx = downloader.ctx();
+    let stream = ctx
+        .retry("获取音频地址", || {
+            get_song_stream(client, song.id, headers.clone())
+        })
+        .await?;
+    if !Path::new(save_path).exists() {
+        std::fs::create_dir_all(save_path)?;
+    }
+    let output_path = format!("{}/{}.{}", save_path, name, audio_ext(&stream.urls[0]));
+    if Path::new(&output_path).exists() {
+        println!("{} already exists", output_path);
+    } else {
+        println!("downloading {}", name);
+        let streams = [Stream {
+            path: output_path.clone(),
+            ..stream
+        }];
+        let result = ctx
+            .retry_verify(&format!("下载 {}", name), || {
+                downloader.fetch_streams(&streams)
+            })
+            .await;
+        if result.is_err() {
+            downloader.cleanup(&[output_path.as_str()]);
+        }
+        result?;
+        append_history(&format!("au{}", song.id), name).await?;
+    }
+
+    // 封面和歌词是附带的，失败不影响音频
+    for (url, ext) in [(&song.cover, "jpg"), (&song.lyric, "lrc")] {
+        let path = format!("{}/{}.{}", save_path, name, ext);
+        if url.is_empty() || Path::new(&path).exists() {
+            continue;
+        }
+        let result = ctx
+            .retry(&format!("下载 {}", path), || {
+                save_file(client, url, &path, headers.clone())
+            })
+            .await;
+        if let Err(e) = result {
+            println!("failed to save {}: {:#}", path, e);
+        }
+    }
+    Ok(())
+}
+
+async fn save_file(client: &Client, url: &str, path: &str, headers: HeaderMap) -> Result<()> {
+    let bytes = client
+        .get(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    tokio::fs::write(path, &bytes).await?;
+    Ok(())
+}
+
+/// 下载单曲 `au…`
+pub async fn down_main(
+    au_id: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let song = ctx
+        .retry("获取歌曲信息", || {
+            get_song(&client, au_id, headers.clone())
+        })
+        .await?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, song.title.clone())).await;
+    }
+    let downloader = Downloader::new(client.clone(), headers.clone(), ctx);
+    down_song(
+        &client,
+        &headers,
+        &downloader,
+        &song,
+        &song.title,
+        save_path,
+    )
+    .await?;
+    Ok(song.title)
+}
+
+/// 歌单标题和封面
+async fn get_menu_info(
+    client: &Client,
+    am_id: &str,
+    headers: HeaderMap,
+) -> Result<(String, String)> {
+    let url = "https://www.bilibili.com/audio/music-service-c/web/menu/info";
+    let json = get_json(client, url, &[("sid", am_id)], headers).await?;
+    let title = remove_punctuation(json["data"]["title"].as_str().unwrap_or(am_id));
+    let cover = json["data"]["cover"].as_str().unwrap_or("").to_string();
+    Ok((title, cover))
+}
+
+/// 歌单中的一页歌曲，返回歌曲和总页数
+async fn get_menu_page(
+    client: &Client,
+    am_id: &str,
+    pn: u32,
+    headers: HeaderMap,
+) -> Result<(Vec<Song>, u32)> {
+    let url = "https://www.bilibili.com/audio/music-service-c/web/song/of-menu";
+    let (pn, ps) = (pn.to_string(), MENU_PAGE_SIZE.to_string());
+    let query = [("sid", am_id), ("pn", pn.as_str()), ("ps", ps.as_str())];
+    let json = get_json(client, url, &query, headers).await?;
+    let songs = json["data"]["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(parse_song)
+        .collect();
+    let page_count = json["data"]["pageCount"].as_u64().unwrap_or(1) as u32;
+    Ok((songs, page_count))
+}
+
+/// 下载歌单 `am…`，按歌单顺序编号保存到以歌单命名的文件夹
+pub async fn down_menu(
+    am_id: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let (title, _) = ctx
+        .retry("获取歌单信息", || {
+            get_menu_info(&client, am_id, headers.clone())
+        })
+        .await?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, title.clone())).await;
+    }
+    let mut songs = Vec::new();
+    for pn in 1.. {
+        let (page, page_count) = ctx
+            .retry("获取歌单列表", || {
+                get_menu_page(&client, am_id, pn, headers.clone())
+            })
+            .await?;
+        let empty = page.is_empty();
+        songs.extend(page);
+        if empty || pn >= page_count {
+            break;
+        }
+    }
+    let folder = format!("{}/{}", save_path, title);
+    println!("downloading {} songs of {}", songs.len(), title);
+
+    let downloader = Downloader::new(client.clone(), headers.clone(), ctx);
+    let width = songs.len().to_string().len().max(2);
+    let mut failed = Vec::new();
+    for (i, song) in songs.iter().enumerate() {
+        let name = format!("{:0width$} {}", i + 1, song.title, width = width);
+        let result = down_song(&client, &headers, &downloader, song, &name, &folder).await;
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("au{} failed: {:#}", song.id, e);
+                failed.push(format!("au{} {}: {:#}", song.id, song.title, e));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 首歌曲下载失败\n{}",
+            failed.len(),
+            songs.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(title)
+}
+
+pub async fn song_title(au_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let song = get_song(&client, au_id, create_headers(&cookies)).await?;
+    Ok((song.title, song.cover))
+}
+
+pub async fn menu_title(am_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    get_menu_info(&client, am_id, create_headers(&cookies)).await
+}
+
+#[test]
+fn test_parse_song_stream() {
+    let song = parse_song(&serde_json::json!({
+        "id": 1234, "title": "歌/名", "cover": "http://i0.hdslb.com/a.jpg",
+        "lyric": "http://i0.hdslb.com/a.lrc",
+    }))
+    .unwrap();
+    assert_eq!(song.id, 1234);
+    assert!(!song.lyric.is_empty());
+    let stream = parse_stream(&serde_json::json!({
+        "type": 3, "size": 30000000,
+        "cdns": ["https://upos-sz-mirrorkodo.bilivideo.com/ugaxcode/a.flac?deadline=1", "https://b/a.flac"],
+    }))
+    .unwrap();
+    assert_eq!(stream.urls.len(), 2);
+    assert_eq!(audio_ext(&stream.urls[0]), "flac");
+    assert_eq!(audio_ext("https://a/m1234.m4a?e=1"), "m4a");
+    assert!(parse_stream(&serde_json::json!({ "cdns": null })).is_err());
+}
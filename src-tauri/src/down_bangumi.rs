@@ -1,33 +1,140 @@
 use anyhow::{Context, Ok, Result};
 use chrono::Utc;
-use futures_util::TryStreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::header::HeaderMap;
 use reqwest::Client;
+use serde::Serialize;
 use serde_json::{self, Value};
 use std::collections::HashMap;
 use std::path::Path;
-use std::time::Instant;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
 use tokio::sync::mpsc;
 
-use crate::progress;
+use crate::control::{Cancelled, TaskControl};
+use crate::download::{Downloader, Stream, TaskContext};
+use crate::init_::Pages;
+use crate::playurl::{Dash, PlayUrl};
 use crate::refresh_cookie::{create_headers, Cookies};
-use crate::resolution;
+use crate::resolution::{self, Quality};
+use crate::resume;
+use crate::retry::ApiError;
+use crate::verify;
 
+/// selection 为 None 时：ss 链接下载全部正片，ep 链接只下载该集
 pub async fn down_main(
     (ep_id, season_id): (&str, &str),
+    selection: Option<&EpisodeSelection>,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<()> {
-    download_bangumi(ep_id, season_id, rsl, save_path, progress_tx, title_tx).await?;
+    download_bangumi(ep_id, season_id, selection, rsl, save_path, ctx, title_tx).await?;
     Ok(())
 }
 
+/// 番剧的一集（正片或花絮、PV 等番外）
+#[derive(Debug, Clone, Serialize)]
+pub struct BangumiEpisode {
+    pub ep_id: i64,
+    /// 在正片中的序号，从 1 开始；番外为 None
+    pub index: Option<u32>,
+    pub title: String,
+    pub long_title: String,
+    /// 角标，如“会员”“预告”
+    pub badge: String,
+    /// 所属分区，正片为“正片”
+    pub section: String,
+}
+
+/// 正片在前，各番外分区按顺序在后
+pub fn list_episodes(json: &Value) -> Vec<BangumiEpisode> {
+    let parse = |ep: &Value, index: Option<u32>, section: &str| {
+        Some(BangumiEpisode {
+            ep_id: ep["ep_id"].as_i64().or(ep["id"].as_i64())?,
+            index,
+            title: ep["title"].as_str().unwrap_or("").to_string(),
+            long_title: ep["long_title"].as_str().unwrap_or("").to_string(),
+            badge: ep["badge"].as_str().unwrap_or("").to_string(),
+            section: section.to_string(),
+        })
+    };
+    let main = json["result"]["episodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(i, ep)| parse(ep, Some(i as u32 + 1), "正片"));
+    let extras = json["result"]["section"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|section| {
+            let title = section["title"].as_str().unwrap_or("番外");
+            section["episodes"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(move |ep| parse(ep, None, title))
+        });
+    main.chain(extras).collect()
+}
+
+/// 番剧的剧集选择，来自链接中的 `eps` 参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpisodeSelection {
+    /// 要下载的正片，None 为不下载正片
+    pub main: Option<Pages>,
+    /// 是否下载番外（PV、花絮、SP 等）
+    pub extras: bool,
+}
+
+impl Default for EpisodeSelection {
+    fn default() -> Self {
+        Self {
+            main: Some(Pages::All),
+            extras: false,
+        }
+    }
+}
+
+impl EpisodeSelection {
+    /// 解析 `1-12,15`、`main`、`extras`、`all`，可组合如 `1-3,extras`
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut selection = Self {
+            main: None,
+            extras: false,
+        };
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.to_ascii_lowercase().as_str() {
+                "all" => {
+                    selection.main = Some(Pages::All);
+                    selection.extras = true;
+                }
+                "main" => selection.main = Some(Pages::All),
+                "extras" | "extra" | "sp" => selection.extras = true,
+                _ => ranges.push(part),
+            }
+        }
+        if !ranges.is_empty() && selection.main != Some(Pages::All) {
+            selection.main = Some(Pages::parse(&ranges.join(","))?);
+        }
+        if selection.main.is_none() && !selection.extras {
+            return Err(anyhow::anyhow!("Empty episode selection"));
+        }
+        Ok(selection)
+    }
+
+    fn contains(&self, episode: &BangumiEpisode) -> bool {
+        match episode.index {
+            Some(index) => self.main.as_ref().is_some_and(|m| m.contains(index)),
+            None => self.extras,
+        }
+    }
+}
+
 /// 获取视频播放地址
 async fn get_playurl(
     client: &Client,
@@ -37,8 +144,7 @@ async fn get_playurl(
     rsl: &str,
 ) -> Result<Value> {
     let url = "https://api.bilibili.com/pgc/player/web/playurl";
-    let qn = resolution::qn(rsl);
-    let fnval = resolution::fnval(rsl);
+    let (qn, fnval) = resolution::playurl_params(rsl);
     println!("fnval: {}", fnval);
     println!("qn: {}", qn);
     let params: HashMap<&str, &str> = [
@@ -46,8 +152,8 @@ async fn get_playurl(
         ("bvid", ""),
         ("ep_id", ep_id),
         ("cid", cid),
-        ("qn", qn),
-        ("fnval", fnval),
+        ("qn", qn.as_str()),
+        ("fnval", fnval.as_str()),
         ("fnver", "0"),
         ("fourk", "1"),
         ("session", ""),
@@ -64,6 +170,7 @@ async fn get_playurl(
         .query(&params)
         .send()
         .await
+        .and_then(|r| r.error_for_status())
         .context("Failed to send request to Bilibili play URL API")?;
 
     let resp_text = response
@@ -72,204 +179,312 @@ async fn get_playurl(
         .context("Failed to read response text from play URL API")?;
     let resp_json: Value = serde_json::from_str(&resp_text)
         .context("Failed to parse JSON response from play URL API")?;
+    ApiError::check(&resp_json)?;
 
     Ok(resp_json)
 }
 
-/// 获取json文件中的视频文件地址
-fn get_file_url(response: &Value, rsl: &str) -> Result<(String, String, i32)> {
-    let qn: i32 = resolution::qn(rsl).parse().unwrap();
-    println!("get file url qn: {}", qn);
-    let video_index = response["result"]["dash"]["video"]
-        .as_array()
-        .context("Missing or invalid video array in response JSON")?
-        .iter()
-        .enumerate()
-        .filter(|(_, v)| v["id"] == qn)
-        .max_by_key(|(_, v)| v["bandwidth"].as_u64().unwrap_or(0))
-        .map(|(index, _)| index)
-        .unwrap_or(0);
-    println!("video_index: {}", video_index);
-
-    let audio_index = response["result"]["dash"]["audio"]
-        .as_array()
-        .context("Missing or invalid audio array in response JSON")?
-        .iter()
-        .enumerate()
-        .max_by_key(|(_, a)| a["size"].as_i64().unwrap_or(0))
-        .map(|(i, _)| i)
-        .context("No valid audio streams found")?;
-
-    let url_video = response["result"]["dash"]["video"][video_index]["baseUrl"]
-        .as_str()
-        .unwrap_or("")
-        .to_string();
-    let url_audio = response["result"]["dash"]["audio"][audio_index]["baseUrl"]
-        .as_str()
-        .unwrap_or("")
-        .to_string();
-    let qn = response["result"]["dash"]["video"][video_index]["id"]
-        .as_i64()
-        .unwrap_or(0) as i32;
-
-    Ok((url_video.to_string(), url_audio.to_string(), qn))
-}
-
-async fn down_from_url(
-    url: &str,
-    client: Client,
-    headers: HeaderMap,
-    path: &str,
-    progress_tx: Option<&mpsc::Sender<progress::DownloadProgress>>,
-    file_index: u32,
-    file_count: u32,
-) -> Result<()> {
-    let resp = client
-        .get(url)
-        .headers(headers.clone())
-        .send()
-        .await
-        .context("Failed to download video stream")?;
-    let total_size = resp.content_length().unwrap_or(0);
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) ")?
-            .progress_chars("=> "),
-    );
-
-    pb.enable_steady_tick(std::time::Duration::from_millis(100));
-
-    let mut file = File::create(&path).await?;
-    let mut stream = resp.bytes_stream();
-    let mut downloaded: u64 = 0;
-    let mut last_emit = Instant::now();
-    let mut last_downloaded: u64 = 0;
-    const EMIT_INTERVAL_MS: u64 = 200;
-
-    while let Some(chunk) = stream.try_next().await? {
-        file.write_all(&chunk).await?;
-        let len = chunk.len() as u64;
-        downloaded += len;
-        pb.inc(len);
-
-        if let Some(tx) = progress_tx {
-            if last_emit.elapsed().as_millis() >= EMIT_INTERVAL_MS as u128
-                || downloaded >= total_size
-            {
-                let elapsed_secs = last_emit.elapsed().as_secs_f64().max(0.001);
-                let speed = (downloaded - last_downloaded) as f64 / elapsed_secs;
-                let eta_secs = if speed > 0.0 && total_size > downloaded {
-                    ((total_size - downloaded) as f64 / speed) as u64
-                } else {
-                    0
-                };
-                let percent = if total_size > 0 {
-                    100.0 * downloaded as f64 / total_size as f64
-                } else {
-                    0.0
-                };
-                let _ = tx
-                    .send(progress::DownloadProgress {
-                        downloaded,
-                        total: total_size,
-                        percent,
-                        speed,
-                        eta_secs,
-                        file_index,
-                        file_count,
-                    })
-                    .await;
-                last_emit = Instant::now();
-                last_downloaded = downloaded;
-            }
-        }
-    }
-    pb.set_position(total_size);
-    pb.finish_with_message("Downloaded stream");
-    Ok(())
-}
-
 /// 下载番剧文件
 async fn down_file_bangumi(
     url_response: Value,
     name_response: Value,
     ep_id: &str,
-    client: &Client,
-    headers: HeaderMap,
+    downloader: &Downloader,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
 ) -> Result<()> {
-    let (url_video, url_audio, qn) = get_file_url(&url_response, rsl)?;
-    let qn_c = resolution::qn(rsl);
-    if qn != qn_c.parse::<i32>().unwrap() {
+    let play_url = PlayUrl::pgc(&url_response)?;
+    if resolution::is_audio_only(rsl) {
+        let name = remove_punctuation(&get_bangumi_name_from_json(name_response, ep_id));
+        return down_audio(
+            downloader,
+            play_url.dash()?,
+            &name,
+            &format!("ep{}", ep_id),
+            &save_path,
+        )
+        .await
+        .map(|_| ());
+    }
+    let requested = Quality::requested(rsl);
+    let (url_video, url_audio, qn, codec) =
+        play_url.select(requested.qn(), &downloader.ctx().codec)?;
+    if qn as i64 != requested.qn() {
         println!("此分辨率不存在，将下载默认分辨率");
     }
-    let qn_str = qn.to_string();
-    let rsl = resolution::rsl(&qn_str);
+    let rsl = resolution::quality_name(qn as i64);
 
     let bangumi_name_temp = get_bangumi_name_from_json(name_response, ep_id);
     let bangumi_name = remove_punctuation(&bangumi_name_temp);
 
-    let bangumi_name = format!("{} {}", bangumi_name, rsl);
-
-    let time = Utc::now() + chrono::Duration::hours(8);
-    let time_ = time.format("%Y-%m-%d %H:%M:%S");
-    let data = format!("{}\tep{}\t{}\t\n", time_, ep_id, bangumi_name);
-    let path = Path::new("dat.log");
-    if !path.exists() {
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(data.as_bytes()).await?;
-    } else {
-        let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
-            .open(path)
-            .await?;
-        file.write_all(data.as_bytes()).await?;
+    downloader.ctx().record_codec(codec);
+    if let Some(file) = existing_mp4(&save_path, &bangumi_name, &rsl, codec) {
+        println!("{} already exists", file);
+        return Ok(());
     }
+    let bangumi_name = output_name(&bangumi_name, &rsl, codec);
 
     if !Path::new(&save_path).exists() {
         std::fs::create_dir_all(&save_path)?;
     }
     let video_path = format!("{}/{}_video.m4s", save_path, bangumi_name);
     let audio_path = format!("{}/{}_audio.m4s", save_path, bangumi_name);
-    let output_path = format!("{}/{}.mp4", save_path, bangumi_name);
+    println!("downloading {}", bangumi_name);
+
+    let streams = vec![
+        Stream {
+            path: video_path,
+            ..url_video
+        },
+        Stream {
+            path: audio_path,
+            ..url_audio
+        },
+    ];
+    fetch_and_concat(downloader, &streams, &bangumi_name, &save_path).await?;
+    println!("Concat completed for {}", bangumi_name);
+    append_history(&format!("ep{}", ep_id), &bangumi_name).await?;
+    Ok(())
+}
+
+/// 输出文件名（不含扩展名）：名称、清晰度和视频编码
+pub fn output_name(name: &str, rsl: &str, codec: &str) -> String {
+    format!("{} {} {}", name, rsl, codec).trim_end().to_string()
+}
+
+/// 已下载的 mp4 文件名：当前的命名，或者文件名里还没有视频编码时的旧命名
+pub fn existing_mp4(save_path: &str, name: &str, rsl: &str, codec: &str) -> Option<String> {
+    [output_name(name, rsl, codec), output_name(name, rsl, "")]
+        .into_iter()
+        .map(|name| format!("{}.mp4", name))
+        .find(|file| Path::new(&format!("{}/{}", save_path, file)).exists())
+}
+
+/// 在下载历史 dat.log 中追加一条记录
+pub async fn append_history(id: &str, name: &str) -> Result<()> {
+    let time = Utc::now() + chrono::Duration::hours(8);
+    let time_ = time.format("%Y-%m-%d %H:%M:%S");
+    let data = format!("{}\t{}\t{}\t\n", time_, id, name);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open("dat.log")
+        .await?;
+    file.write_all(data.as_bytes()).await?;
+    Ok(())
+}
+
+/// 仅音频模式选中的音轨
+#[derive(Debug, Clone)]
+pub struct AudioTrack {
+    pub stream: Stream,
+    /// 写进文件名的音质，如 Hi-Res、Dolby、192K
+    pub label: String,
+    /// 输出文件扩展名
+    pub ext: &'static str,
+}
 
+/// 选出最好的音轨：Hi-Res 无损优先，其次杜比全景声，最后是码率最高的普通音轨
+pub fn best_audio(dash: &Dash) -> Result<AudioTrack> {
+    if let Some(flac) = dash.flac.as_ref().and_then(|f| f.audio.as_ref()) {
+        return Ok(AudioTrack {
+            stream: flac.stream()?,
+            label: "Hi-Res".to_string(),
+            ext: "flac",
+        });
+    }
+    let dolby = dash.dolby.as_ref().and_then(|d| d.audio.as_deref());
+    if let Some(dolby) = dolby.unwrap_or_default().iter().max_by_key(|a| a.bandwidth) {
+        return Ok(AudioTrack {
+            stream: dolby.stream()?,
+            label: "Dolby".to_string(),
+            ext: "m4a",
+        });
+    }
+    let audio = dash
+        .audios()
+        .iter()
+        .max_by_key(|a| a.bandwidth)
+        .context("No valid audio streams found")?;
+    let label = match audio.id {
+        30216 => "64K",
+        30232 => "132K",
+        30280 => "192K",
+        _ => "AUDIO",
+    };
+    Ok(AudioTrack {
+        stream: audio.stream()?,
+        label: label.to_string(),
+        ext: "m4a",
+    })
+}
+
+/// 仅下载音轨并转封装为 m4a 或 flac，不下载视频，返回输出文件名
+pub async fn down_audio(
+    downloader: &Downloader,
+    dash: &Dash,
+    name: &str,
+    id: &str,
+    save_path: &str,
+) -> Result<String> {
+    let track = best_audio(dash)?;
+    let name = format!("{} {}", name, track.label);
+
+    if !Path::new(save_path).exists() {
+        std::fs::create_dir_all(save_path)?;
+    }
+    let file_name = format!("{}.{}", name, track.ext);
+    let output_path = format!("{}/{}", save_path, file_name);
     if Path::new(&output_path).exists() {
-        println!("{} already exists", bangumi_name);
-        return Ok(());
+        println!("{} already exists", output_path);
+        return Ok(file_name);
     }
-    println!("downloading {}", bangumi_name);
+    println!("downloading {}", name);
+
+    let streams = [Stream {
+        path: format!("{}/{}_audio.m4s", save_path, name),
+        ..track.stream
+    }];
+    let result = downloader
+        .ctx()
+        .retry_verify(&format!("下载并校验 {}", name), || async {
+            downloader.fetch_streams(&streams).await?;
+            extract_audio(&streams[0].path, &output_path, downloader.control()).await
+        })
+        .await;
+    if result.is_err() {
+        downloader.cleanup(&[streams[0].path.as_str()]);
+    }
+    result?;
+    append_history(id, &name).await?;
+    Ok(file_name)
+}
 
-    let urls = vec![(url_video, video_path), (url_audio, audio_path)];
-    for (file_index, (url, path)) in urls.iter().enumerate() {
-        let client = client.clone();
-        let headers = headers.clone();
-        let tx_ref = progress_tx.as_ref();
-        down_from_url(url, client, headers, path, tx_ref, file_index as u32, 2).await?;
+/// 把 m4s 音轨转封装到输出文件，校验通过后删除 m4s
+async fn extract_audio(audio_path: &str, output_path: &str, control: &TaskControl) -> Result<()> {
+    let tmp = resume::temp_output_path(output_path);
+    let format = if output_path.ends_with(".flac") {
+        "flac"
+    } else {
+        "ipod"
+    };
+    run_ffmpeg(
+        &[
+            "-i",
+            audio_path,
+            "-vn",
+            "-c:a",
+            "copy",
+            "-y",
+            "-f",
+            format,
+            tmp.as_str(),
+            "-hide_banner",
+            "-loglevel",
+            "error",
+        ],
+        &tmp,
+        control,
+    )
+    .await?;
+    if let Err(e) = verify::probe_audio(&tmp).await {
+        for path in [tmp.as_str(), audio_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        return Err(e);
     }
+    std::fs::rename(&tmp, output_path).context("Failed to rename audio file")?;
+    println!("{}", output_path);
+    remove_if_exists(audio_path)?;
+    Ok(())
+}
 
-    concat_video_audio(bangumi_name.clone(), save_path.clone()).await?;
-    println!("Concat completed for {}", bangumi_name);
+/// 运行 ffmpeg 写出临时文件，任务取消或失败时结束进程并删除临时文件
+async fn run_ffmpeg(args: &[&str], tmp: &str, control: &TaskControl) -> Result<()> {
+    // 上次中断留下的临时文件
+    remove_if_exists(tmp)?;
+    let mut child = Command::new("ffmpeg")
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to execute ffmpeg")?;
+
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = control.cancelled() => {
+            let _ = child.kill().await;
+            let _ = std::fs::remove_file(tmp);
+            return Err(Cancelled.into());
+        }
+    };
+
+    if !status.success() {
+        eprintln!("Fail!");
+        let _ = std::fs::remove_file(tmp);
+        return Err(anyhow::anyhow!("ffmpeg exited with {}", status));
+    }
     Ok(())
 }
 
-/// 合并视频和音频文件
-pub async fn concat_video_audio(name: String, save_path: String) -> Result<()> {
+/// 删除文件，文件已经不存在时不算错误
+fn remove_if_exists(path: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 下载音视频流并合并，校验失败时删除文件重新下载
+pub async fn fetch_and_concat(
+    downloader: &Downloader,
+    streams: &[Stream],
+    name: &str,
+    save_path: &str,
+) -> Result<()> {
+    let result = downloader
+        .ctx()
+        .retry_verify(&format!("下载并校验 {}", name), || async {
+            downloader.fetch_streams(streams).await?;
+            concat_video_audio(
+                name.to_string(),
+                save_path.to_string(),
+                downloader.control(),
+            )
+            .await
+        })
+        .await;
+    if result.is_err() {
+        let paths: Vec<&str> = streams.iter().map(|s| s.path.as_str()).collect();
+        downloader.cleanup(&paths);
+    }
+    result
+}
+
+/// 合并视频和音频文件，任务取消时结束 ffmpeg 进程
+pub async fn concat_video_audio(
+    name: String,
+    save_path: String,
+    control: &TaskControl,
+) -> Result<()> {
     if !Path::new(&save_path).exists() {
         std::fs::create_dir_all(&save_path)?;
     }
     let name_mp4 = format!("{}/{}.mp4", save_path, name);
     let name_video = format!("{}/{}_video.m4s", save_path, name);
     let name_audio = format!("{}/{}_audio.m4s", save_path, name);
+    // 先写到临时文件，校验通过后再改名，中途崩溃不会留下半截的 mp4
+    let name_tmp = resume::temp_output_path(&name_mp4);
+    let control = control.clone();
     let handle = tokio::spawn(async move {
         let name_mp4 = name_mp4;
         if Path::new(&name_mp4).exists() {
-            return;
+            return Ok(());
         }
-        let status = Command::new("ffmpeg")
-            .args(&[
+        run_ffmpeg(
+            &[
                 "-i",
                 name_video.as_str(),
                 "-i",
@@ -286,29 +501,43 @@ pub async fn concat_video_audio(name: String, save_path: String) -> Result<()> {
                 "-y",
                 "-movflags",
                 "+faststart",
-                name_mp4.as_str(),
+                "-f",
+                "mp4",
+                name_tmp.as_str(),
                 "-hide_banner",
                 "-stats",
                 "-loglevel",
                 "error",
-            ])
-            .stdin(std::process::Stdio::null())
-            .status()
-            .await
-            .expect("Failed to execute ffmpeg");
-
-        if status.success() {
-            println!("{}", name_mp4);
-            std::fs::remove_file(name_video).unwrap();
-            std::fs::remove_file(name_audio).unwrap();
-        } else {
-            eprintln!("Fail!");
+            ],
+            &name_tmp,
+            &control,
+        )
+        .await?;
+        // 校验通过后才删除源文件；不通过则全部删除，重新下载
+        if let Err(e) = verify::probe_mp4(&name_tmp).await {
+            for path in [&name_tmp, &name_video, &name_audio] {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(e);
         }
+        std::fs::rename(&name_tmp, &name_mp4).context("Failed to rename muxed file")?;
+        println!("{}", name_mp4);
+        remove_if_exists(&name_video)?;
+        remove_if_exists(&name_audio)?;
+        Ok(())
     });
-    handle.await?;
+    handle.await??;
     Ok(())
 }
 
+/// 列出番剧的正片和番外
+pub async fn season_episodes(ep_id: &str, season_id: &str) -> Result<Vec<BangumiEpisode>> {
+    let client = reqwest::Client::new();
+    let cookie = read_cookie_or_not(Path::new("./load")).await?;
+    let json = get_bangumi_name(&client, ep_id, season_id, create_headers(&cookie)).await?;
+    Ok(list_episodes(&json))
+}
+
 /// 获取番剧名称
 async fn get_bangumi_name(
     client: &Client,
@@ -325,42 +554,57 @@ async fn get_bangumi_name(
         .headers(headers)
         .query(&params)
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
     let resp_text = response.text().await?;
     let resp_text_str = std::str::from_utf8(resp_text.as_bytes()).unwrap_or("");
     let resp_json: Value = serde_json::from_str(resp_text_str)?;
+    ApiError::check(&resp_json)?;
 
     Ok(resp_json)
 }
 
 /// 从json文件中获取该ep_id对应的番剧名称
 fn get_bangumi_name_from_json(json: Value, ep_id: &str) -> String {
-    let ep_id = ep_id.parse::<i64>().unwrap();
-    let ep_id_index: usize = json["result"]["episodes"]
-        .as_array()
-        .unwrap_or(&std::vec::Vec::new())
-        .iter()
-        .position(|episode| episode["ep_id"].as_i64().unwrap_or(0) == ep_id)
-        .unwrap_or(0);
-    let bangumi_name = json["result"]["episodes"][ep_id_index]["share_copy"]
-        .as_str()
-        .unwrap_or("");
-    bangumi_name.to_string()
+    let Some(episode) = find_episode(&json, ep_id) else {
+        return String::new();
+    };
+    match episode["share_copy"].as_str().filter(|s| !s.is_empty()) {
+        Some(name) => name.to_string(),
+        // 部分番外没有 share_copy
+        None => format!(
+            "{} {} {}",
+            json["result"]["title"].as_str().unwrap_or(""),
+            episode["title"].as_str().unwrap_or(""),
+            episode["long_title"].as_str().unwrap_or("")
+        )
+        .trim()
+        .to_string(),
+    }
 }
 
 ///
 fn get_bangumi_pic(json: Value, ep_id: &str) -> String {
-    let ep_id = ep_id.parse::<i64>().unwrap();
-    let ep_id_index: usize = json["result"]["episodes"]
+    find_episode(&json, ep_id)
+        .and_then(|episode| episode["cover"].as_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// 在正片和番外中查找剧集，找不到时退回第一集
+fn find_episode<'a>(json: &'a Value, ep_id: &str) -> Option<&'a Value> {
+    let ep_id = ep_id.parse::<i64>().unwrap_or(0);
+    let main = json["result"]["episodes"].as_array().into_iter().flatten();
+    let extras = json["result"]["section"]
         .as_array()
-        .unwrap_or(&std::vec::Vec::new())
-        .iter()
-        .position(|episode| episode["ep_id"].as_i64().unwrap_or(0) == ep_id)
-        .unwrap_or(0);
-    let bangumi_pic = json["result"]["episodes"][ep_id_index]["cover"]
-        .as_str()
-        .unwrap_or("");
-    bangumi_pic.to_string()
+        .into_iter()
+        .flatten()
+        .flat_map(|section| section["episodes"].as_array().into_iter().flatten());
+    let matches = |ep: &&Value| ep["ep_id"].as_i64().or(ep["id"].as_i64()) == Some(ep_id);
+    main.clone()
+        .chain(extras)
+        .find(matches)
+        .or_else(|| main.clone().next())
 }
 
 /// 去除文件名字符串中的windows不允许的标点符号
@@ -397,18 +641,21 @@ async fn down_season(
     name_response: Value,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    downloader: &Downloader,
 ) -> Result<()> {
-    let url_response = get_playurl(&client, &ep_id_cp, "", headers.clone(), rsl).await?;
+    let url_response = downloader
+        .ctx()
+        .retry("获取播放地址", || {
+            get_playurl(&client, &ep_id_cp, "", headers.clone(), rsl)
+        })
+        .await?;
     down_file_bangumi(
         url_response,
         name_response.clone(),
         &ep_id_cp,
-        &client,
-        headers.clone(),
+        downloader,
         rsl,
         save_path.clone(),
-        progress_tx,
     )
     .await?;
     Ok(())
@@ -418,17 +665,24 @@ async fn down_season(
 async fn download_bangumi(
     ep_id: &str,
     season_id: &str,
+    selection: Option<&EpisodeSelection>,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<()> {
     let client = reqwest::Client::new();
     let path = Path::new("./load");
     let cookie = read_cookie_or_not(&path).await?;
     let headers = create_headers(&cookie);
-    let name_response = get_bangumi_name(&client, &ep_id, &season_id, headers.clone()).await?;
-    let display_title = if !season_id.is_empty() {
+    let downloader = Downloader::new(client.clone(), headers.clone(), ctx);
+    let name_response = ctx
+        .retry("获取番剧信息", || {
+            get_bangumi_name(&client, ep_id, season_id, headers.clone())
+        })
+        .await?;
+    let whole_season = !season_id.is_empty() || selection.is_some();
+    let display_title = if whole_season {
         name_response["result"]["title"]
             .as_str()
             .unwrap_or("")
@@ -440,44 +694,87 @@ async fn download_bangumi(
     if let Some((idx, tx)) = &title_tx {
         let _ = tx.send((*idx, display_title.clone())).await;
     }
-    if season_id != "" {
-        for i in 0..name_response["result"]["episodes"]
-            .as_array()
-            .unwrap()
-            .len()
-        {
-            let ep_id_cp = name_response["result"]["episodes"][i]["ep_id"]
-                .as_i64()
-                .unwrap_or(0)
-                .to_string();
-            down_season(
-                ep_id_cp,
+    if whole_season {
+        let default_selection = EpisodeSelection::default();
+        let selection = selection.unwrap_or(&default_selection);
+        let episodes: Vec<BangumiEpisode> = list_episodes(&name_response)
+            .into_iter()
+            .filter(|ep| selection.contains(ep))
+            .collect();
+        if episodes.is_empty() {
+            return Err(anyhow::anyhow!("没有符合条件的剧集"));
+        }
+        // 单集失败不影响其余剧集，全部尝试后再汇总
+        let mut failed = Vec::new();
+        for episode in &episodes {
+            let ep_id_cp = episode.ep_id.to_string();
+            let result = down_season(
+                ep_id_cp.clone(),
                 &client,
                 headers.clone(),
                 name_response.clone(),
                 rsl,
                 save_path.clone(),
-                progress_tx.clone(),
+                &downloader,
             )
-            .await?;
+            .await;
+            match result {
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => {
+                    println!("ep {} failed: {:#}", ep_id_cp, e);
+                    failed.push(format!("ep{}: {:#}", ep_id_cp, e));
+                }
+                _ => {}
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{}/{} 集下载失败\n{}",
+                failed.len(),
+                episodes.len(),
+                failed.join("\n")
+            );
         }
     } else {
-        let url_response = get_playurl(&client, &ep_id, "", headers.clone(), rsl).await?;
+        let url_response = ctx
+            .retry("获取播放地址", || {
+                get_playurl(&client, ep_id, "", headers.clone(), rsl)
+            })
+            .await?;
         down_file_bangumi(
             url_response,
             name_response,
             ep_id,
-            &client,
-            headers,
+            &downloader,
             rsl,
             save_path.clone(),
-            progress_tx,
         )
         .await?;
     }
     Ok(())
 }
 
+/// 番剧介绍页 `md…` 对应的 season_id
+pub async fn media_season_id(media_id: &str) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookie = read_cookie_or_not(Path::new("./load")).await?;
+    let json: Value = client
+        .get("https://api.bilibili.com/pgc/review/user")
+        .headers(create_headers(&cookie))
+        .query(&[("media_id", media_id)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Failed to parse media info")?;
+    ApiError::check(&json)?;
+    json["result"]["media"]["season_id"]
+        .as_i64()
+        .map(|id| id.to_string())
+        .context("Media info does not contain season_id")
+}
+
 pub async fn bangumi_title(ep_id: &str, season_id: &str) -> Result<(String, String)> {
     let client = reqwest::Client::new();
     let path = Path::new("./load");
@@ -504,3 +801,84 @@ pub async fn bangumi_title(ep_id: &str, season_id: &str) -> Result<(String, Stri
     //get_pic(&bangumi_pic).await?;
     Ok((bangumi_name, bangumi_pic))
 }
+
+#[test]
+fn test_best_audio() {
+    let parse = |json: &Value| -> Dash { serde_json::from_value(json.clone()).unwrap() };
+    let mut dash = serde_json::json!({
+        "audio": [
+            { "id": 30216, "bandwidth": 67000, "baseUrl": "https://a/64k.m4s" },
+            { "id": 30280, "bandwidth": 190000, "baseUrl": "https://a/192k.m4s" },
+        ],
+        "dolby": { "type": 0, "audio": null },
+        "flac": null,
+    });
+    let track = best_audio(&parse(&dash)).unwrap();
+    assert_eq!((track.label.as_str(), track.ext), ("192K", "m4a"));
+    assert_eq!(track.stream.urls, vec!["https://a/192k.m4s"]);
+
+    dash["dolby"]["audio"] = serde_json::json!([
+        { "id": 30250, "bandwidth": 440000, "baseUrl": "https://a/dolby.m4s" },
+    ]);
+    assert_eq!(best_audio(&parse(&dash)).unwrap().label, "Dolby");
+
+    dash["flac"] = serde_json::json!({
+        "display": true,
+        "audio": { "id": 30251, "bandwidth": 1000000, "baseUrl": "https://a/flac.m4s" },
+    });
+    let track = best_audio(&parse(&dash)).unwrap();
+    assert_eq!((track.label.as_str(), track.ext), ("Hi-Res", "flac"));
+    assert!(best_audio(&parse(&serde_json::json!({}))).is_err());
+}
+
+#[test]
+fn test_episode_selection() {
+    let json = serde_json::json!({ "result": {
+        "title": "番剧",
+        "episodes": [
+            { "ep_id": 11, "title": "1", "badge": "" },
+            { "ep_id": 12, "title": "2", "badge": "会员" },
+            { "ep_id": 13, "title": "3" },
+        ],
+        "section": [{ "title": "PV", "episodes": [{ "id": 21, "title": "PV1", "badge": "预告" }] }],
+    }});
+    let episodes = list_episodes(&json);
+    assert_eq!(episodes.len(), 4);
+    assert_eq!(
+        (episodes[3].index, episodes[3].section.as_str()),
+        (None, "PV")
+    );
+    let picked = |s: &str| -> Vec<i64> {
+        let selection = EpisodeSelection::parse(s).unwrap();
+        episodes
+            .iter()
+            .filter(|ep| selection.contains(ep))
+            .map(|ep| ep.ep_id)
+            .collect()
+    };
+    assert_eq!(picked("1,3"), vec![11, 13]);
+    assert_eq!(picked("extras"), vec![21]);
+    assert_eq!(picked("2-3,extras"), vec![12, 13, 21]);
+    assert_eq!(picked("all"), vec![11, 12, 13, 21]);
+    assert!(EpisodeSelection::parse("").is_err());
+    assert_eq!(get_bangumi_name_from_json(json, "21"), "番剧 PV1");
+}
+
+#[test]
+fn test_existing_mp4() {
+    let test_dir = crate::resume::TestDir::new("existing_mp4");
+    let dir = &test_dir.0;
+    let save_path = dir.to_str().unwrap();
+    assert_eq!(existing_mp4(save_path, "a", "1080P", "AVC"), None);
+    // 加入编码前下载的文件不再重复下载
+    std::fs::write(dir.join("a 1080P.mp4"), b"").unwrap();
+    assert_eq!(
+        existing_mp4(save_path, "a", "1080P", "AVC").as_deref(),
+        Some("a 1080P.mp4")
+    );
+    std::fs::write(dir.join("a 1080P AVC.mp4"), b"").unwrap();
+    assert_eq!(
+        existing_mp4(save_path, "a", "1080P", "AVC").as_deref(),
+        Some("a 1080P AVC.mp4")
+    );
+}
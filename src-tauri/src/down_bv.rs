@@ -1,30 +1,48 @@
-use crate::down_bangumi::{concat_video_audio, read_cookie_or_not, remove_punctuation};
-use crate::progress;
+use crate::control::Cancelled;
+use crate::down_bangumi::{
+    append_history, down_audio, existing_mp4, fetch_and_concat, output_name, read_cookie_or_not,
+    remove_punctuation,
+};
+use crate::down_interactive;
+use crate::download::{Downloader, Stream, TaskContext};
+use crate::init_::Pages;
+use crate::playurl::PlayUrl;
 use crate::refresh_cookie::create_headers;
-use crate::resolution;
+use crate::resolution::{self, Quality};
+use crate::retry::ApiError;
 use crate::wbi::get_wbi_keys_main;
 use anyhow::{Context, Ok, Result};
-use chrono::Utc;
-use futures_util::TryStreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
 use qrcode::render::pic;
 use reqwest::header::HeaderMap;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
 use std::path::Path;
-use std::time::Instant;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc;
 
 #[derive(Deserialize, Debug)]
 struct BV {
     bv_id: String,
-    cid: String,
     title: String,
+    pages: Vec<Page>,
+    /// 互动视频，分P信息里只有起始节点
+    #[serde(default)]
+    interactive: bool,
+}
+
+/// 多P视频中的一P
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Page {
+    /// 分P序号，从 1 开始
+    pub page: u32,
+    pub cid: i64,
+    /// 分P标题
+    pub part: String,
+    /// 时长（秒）
+    #[serde(default)]
+    pub duration: u64,
 }
 
 async fn get_bv_play_url(
@@ -36,15 +54,14 @@ async fn get_bv_play_url(
 ) -> Result<Value> {
     let url = "https://api.bilibili.com/x/player/wbi/playurl";
     let wbi_keys = get_wbi_keys_main().await?;
-    let qn = resolution::qn(rsl);
-    let fnval = resolution::fnval(rsl);
+    let (qn, fnval) = resolution::playurl_params(rsl);
     println!("fnval: {}", fnval);
     println!("qn: {}", qn);
     let params: HashMap<&str, &str> = [
         ("bvid", bv_id),
         ("cid", cid),
-        ("qn", qn),
-        ("fnval", fnval),
+        ("qn", qn.as_str()),
+        ("fnval", fnval.as_str()),
         ("fnver", "0"),
         ("fourk", "1"),
         ("session", ""),
@@ -62,13 +79,16 @@ async fn get_bv_play_url(
         .query(&params)
         .send()
         .await?
+        .error_for_status()?
         .text()
         .await?;
     let json: Value = serde_json::from_str(&resp)?;
+    ApiError::check(&json)?;
     Ok(json)
 }
 
-async fn get_bv_cid_title(client: &Client, bv: &str, headers: HeaderMap) -> Result<BV> {
+/// 视频详情接口的原始返回
+pub async fn get_view(client: &Client, bv: &str, headers: HeaderMap) -> Result<Value> {
     let url = "https://api.bilibili.com/x/web-interface/wbi/view";
     let params: HashMap<&str, &str> = [("bvid", bv)].iter().cloned().collect();
     let resp = client
@@ -77,216 +97,109 @@ async fn get_bv_cid_title(client: &Client, bv: &str, headers: HeaderMap) -> Resu
         .query(&params)
         .send()
         .await?
+        .error_for_status()?
         .text()
         .await?;
     let json: Value = serde_json::from_str(&resp)?;
-    let cid = json["data"]["cid"]
-        .as_i64()
-        .map(|cid| cid.to_string())
-        .unwrap_or_else(|| "".to_string());
+    ApiError::check(&json)?;
+    Ok(json)
+}
+
+async fn get_bv_cid_title(client: &Client, bv: &str, headers: HeaderMap) -> Result<BV> {
+    let json = get_view(client, bv, headers).await?;
     let title = json["data"]["title"]
         .as_str()
         .unwrap_or("no title")
         .to_string();
     let title = remove_punctuation(&title);
+    let mut pages: Vec<Page> =
+        serde_json::from_value(json["data"]["pages"].clone()).unwrap_or_default();
+    if pages.is_empty() {
+        // 没有分P信息时按单P处理
+        pages.push(Page {
+            page: 1,
+            cid: json["data"]["cid"].as_i64().unwrap_or(0),
+            part: title.clone(),
+            duration: json["data"]["duration"].as_u64().unwrap_or(0),
+        });
+    }
     let bv = BV {
         bv_id: bv.to_string(),
-        cid: cid,
         title: title,
+        pages,
+        interactive: json["data"]["rights"]["is_stein_gate"].as_i64() == Some(1),
     };
     Ok(bv)
 }
 
-fn get_bv_url(play_url: &Value, rsl: &str) -> Result<(String, String, i32)> {
-    let qn: i32 = resolution::qn(rsl).parse().unwrap();
-    let video_index = play_url["data"]["dash"]["video"]
-        .as_array()
-        .context("Missing or invalid video array in response JSON")?
-        .iter()
-        .enumerate()
-        .filter(|(_, v)| v["id"] == qn)
-        .max_by_key(|(_, v)| v["bandwidth"].as_u64().unwrap_or(0))
-        .map(|(index, _)| index)
-        .unwrap_or(1);
-    println!("video_index: {}", video_index);
-
-    let audio_index = play_url["data"]["dash"]["audio"]
-        .as_array()
-        .context("Missing or invalid audio array in response JSON")?
-        .iter()
-        .enumerate()
-        .max_by_key(|(_, v)| v["bandwidth"].as_i64().unwrap_or(0))
-        .map(|(i, _)| i)
-        .context("No valid audio streams found")?;
-    println!("audio_index: {}", audio_index);
-
-    let video_url = play_url["data"]["dash"]["video"][video_index]["baseUrl"]
-        .as_str()
-        .unwrap_or("")
-        .to_string();
-    let audio_url = play_url["data"]["dash"]["audio"][audio_index]["baseUrl"]
-        .as_str()
-        .unwrap_or("")
-        .to_string();
-    let qn = play_url["data"]["dash"]["video"][video_index]["id"]
-        .as_i64()
-        .unwrap_or(0) as i32;
-    Ok((video_url, audio_url, qn))
-}
-
-async fn down_file_url(
-    url: &str,
-    client: Client,
-    headers: HeaderMap,
-    path: &str,
-    progress_tx: Option<&mpsc::Sender<progress::DownloadProgress>>,
-    file_index: u32,
-    file_count: u32,
-) -> Result<()> {
-    let resp = client
-        .get(url)
-        .headers(headers.clone())
-        .send()
-        .await
-        .context("Failed to download stream")?;
-    let total_size = resp.content_length().unwrap_or(0);
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-            .progress_chars("=> "),
-    );
-    let mut file = File::create(&path)?;
-    let mut stream = resp.bytes_stream();
-    let mut downloaded: u64 = 0;
-    let start = Instant::now();
-    let mut last_emit = Instant::now();
-    let mut last_downloaded: u64 = 0;
-    const EMIT_INTERVAL_MS: u64 = 200;
-
-    while let Some(chunk) = stream.try_next().await? {
-        file.write_all(&chunk)?;
-        let len = chunk.len() as u64;
-        downloaded += len;
-        pb.inc(len);
-
-        if let Some(tx) = progress_tx {
-            if last_emit.elapsed().as_millis() >= EMIT_INTERVAL_MS as u128
-                || downloaded >= total_size
-            {
-                let elapsed_secs = last_emit.elapsed().as_secs_f64().max(0.001);
-                let speed = (downloaded - last_downloaded) as f64 / elapsed_secs;
-                let eta_secs = if speed > 0.0 && total_size > downloaded {
-                    ((total_size - downloaded) as f64 / speed) as u64
-                } else {
-                    0
-                };
-                let percent = if total_size > 0 {
-                    100.0 * downloaded as f64 / total_size as f64
-                } else {
-                    0.0
-                };
-                let _ = tx
-                    .send(progress::DownloadProgress {
-                        downloaded,
-                        total: total_size,
-                        percent,
-                        speed,
-                        eta_secs,
-                        file_index,
-                        file_count,
-                    })
-                    .await;
-                last_emit = Instant::now();
-                last_downloaded = downloaded;
-            }
-        }
-    }
-    pb.finish_with_message("Downloaded video stream");
-    Ok(())
-}
-
-async fn down_file_bv_(
-    client: &Client,
+/// 下载一段视频并合并，完成后以 history_id 写入下载历史，返回输出文件名（不含目录）
+pub async fn down_file_bv_(
+    downloader: &Downloader,
     url: Value,
     name: String,
-    headers: HeaderMap,
     rsl: &str,
-    bv_id: &str,
+    history_id: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
-) -> Result<()> {
-    let (video_url, audio_url, qn) =
-        get_bv_url(&url, rsl).unwrap_or((String::new(), String::new(), 0));
-
-    let qn_c = resolution::qn(rsl);
-    if qn != qn_c.parse::<i32>().unwrap() {
+) -> Result<String> {
+    let play_url = PlayUrl::ugc(&url)?;
+    if resolution::is_audio_only(rsl) {
+        return down_audio(downloader, play_url.dash()?, &name, history_id, &save_path).await;
+    }
+    let requested = Quality::requested(rsl);
+    let (video, audio, qn, codec) = play_url.select(requested.qn(), &downloader.ctx().codec)?;
+    if qn as i64 != requested.qn() {
         println!("此分辨率不存在，将下载默认分辨率");
     }
-    let qn_str = qn.to_string();
-    let rsl = resolution::rsl(&qn_str);
+    let rsl = resolution::quality_name(qn as i64);
 
     if !Path::new(&save_path).exists() {
         std::fs::create_dir_all(&save_path)?;
     }
 
-    let name = format!("{} {}", name, rsl);
+    downloader.ctx().record_codec(codec);
+    if let Some(file_name) = existing_mp4(&save_path, &name, &rsl, codec) {
+        println!("{}/{} already exists", save_path, file_name);
+        return Ok(file_name);
+    }
+    let name = output_name(&name, &rsl, codec);
     let video_path = format!("{}/{}_video.m4s", save_path, name);
     let audio_path = format!("{}/{}_audio.m4s", save_path, name);
-    let output_path = format!("{}/{}.mp4", save_path, name);
-
-    let time = Utc::now() + chrono::Duration::hours(8);
-    let time_ = time.format("%Y-%m-%d %H:%M:%S");
-    let data = format!("{}\t{}\t{}\t\n", time_, bv_id, name);
-    let path = Path::new("dat.log");
-    if !path.exists() {
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(data.as_bytes()).await?;
-    } else {
-        let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
-            .open(path)
-            .await?;
-        file.write_all(data.as_bytes()).await?;
-    }
-
-    if Path::new(&output_path).exists() {
-        println!("{} already exists", output_path);
-        return Ok(());
-    }
+    let file_name = format!("{}.mp4", name);
     println!("downloading {}", name);
 
-    let urls = vec![(video_url, video_path), (audio_url, audio_path)];
-    for (file_index, (url, path)) in urls.iter().enumerate() {
-        let tx_ref = progress_tx.as_ref();
-        down_file_url(
-            url,
-            client.clone(),
-            headers.clone(),
-            path,
-            tx_ref,
-            file_index as u32,
-            2,
-        )
-        .await?;
-    }
-    concat_video_audio(name.clone(), save_path.clone()).await?;
+    let streams = vec![
+        Stream {
+            path: video_path,
+            ..video
+        },
+        Stream {
+            path: audio_path,
+            ..audio
+        },
+    ];
+    fetch_and_concat(downloader, &streams, &name, &save_path).await?;
     println!("Concat completed for {}", name);
-    Ok(())
+    // 合并完成后才写历史，失败或取消的视频下次不会被当成已下载
+    append_history(history_id, &name).await?;
+    Ok(file_name)
 }
 
 async fn bv_down_main(
     bv_id: &str,
+    pages: &Pages,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<String> {
     let client = reqwest::Client::new();
     let path = Path::new("load");
     let cookies = read_cookie_or_not(path).await?;
     let headers = create_headers(&cookies);
-    let bv = get_bv_cid_title(&client, bv_id, headers.clone())
+    let bv = ctx
+        .retry("获取视频信息", || {
+            get_bv_cid_title(&client, bv_id, headers.clone())
+        })
         .await
         .context("Failed to get bv cid title")?;
     if let Some((idx, tx)) = &title_tx {
@@ -294,34 +207,135 @@ async fn bv_down_main(
     }
     println!("{:#?}", bv);
 
-    let play_url = get_bv_play_url(&client, &bv.bv_id, &bv.cid, headers.clone(), rsl)
+    let downloader = Downloader::new(client.clone(), headers.clone(), ctx);
+    if bv.interactive {
+        let entry = (bv_id, bv.title.as_str(), bv.pages[0].cid);
+        down_interactive::down_main(&client, &headers, &downloader, entry, rsl, &save_path).await?;
+        return Ok(bv.title);
+    }
+    let selected: Vec<&Page> = bv.pages.iter().filter(|p| pages.contains(p.page)).collect();
+    if selected.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No such page, the video has {} pages",
+            bv.pages.len()
+        ));
+    }
+    // 单P失败不影响其余分P，全部尝试后再汇总
+    let mut failed = Vec::new();
+    for page in &selected {
+        let result = down_page(&client, &headers, &downloader, &bv, page, rsl, &save_path).await;
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("P{} failed: {:#}", page.page, e);
+                failed.push(format!("P{}: {:#}", page.page, e));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} P 下载失败\n{}",
+            failed.len(),
+            selected.len(),
+            failed.join("\n")
+        );
+    }
+    if bv.pages.len() > 1 && selected.len() == bv.pages.len() {
+        append_history(bv_id, &bv.title).await?;
+    }
+    Ok(bv.title)
+}
+
+/// 下载一P，多P视频的文件名带上分P序号和标题
+async fn down_page(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    bv: &BV,
+    page: &Page,
+    rsl: &str,
+    save_path: &str,
+) -> Result<String> {
+    let name = if bv.pages.len() > 1 {
+        format!(
+            "{} P{} {}",
+            bv.title,
+            page.page,
+            remove_punctuation(&page.part)
+        )
+    } else {
+        bv.title.clone()
+    };
+    // 多P视频每P单独记录，全部完成后再记录整个视频
+    let history_id = if bv.pages.len() > 1 {
+        format!("{}_p{}", bv.bv_id, page.page)
+    } else {
+        bv.bv_id.clone()
+    };
+    down_cid(
+        client,
+        headers,
+        downloader,
+        (&bv.bv_id, page.cid, &history_id),
+        name,
+        rsl,
+        save_path,
+    )
+    .await
+}
+
+/// 下载视频中指定 cid 的一段（分P或互动视频节点），完成后以 history_id 写入历史，返回输出文件名
+pub async fn down_cid(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    (bv_id, cid, history_id): (&str, i64, &str),
+    name: String,
+    rsl: &str,
+    save_path: &str,
+) -> Result<String> {
+    let cid = cid.to_string();
+    let play_url = downloader
+        .ctx()
+        .retry("获取播放地址", || {
+            get_bv_play_url(client, bv_id, &cid, headers.clone(), rsl)
+        })
         .await
         .context("Failed to get bv play url")?;
     down_file_bv_(
-        &client,
+        downloader,
         play_url,
-        bv.title.clone(),
-        headers,
+        name,
         rsl,
-        &bv.bv_id,
-        save_path,
-        progress_tx,
+        history_id,
+        save_path.to_string(),
     )
-    .await?;
-    Ok(bv.title)
+    .await
 }
 
 pub async fn down_main(
     bv_id: &str,
+    pages: &Pages,
     rsl: &str,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<String> {
-    let title = bv_down_main(bv_id, rsl, save_path, progress_tx, title_tx).await?;
+    let title = bv_down_main(bv_id, pages, rsl, save_path, ctx, title_tx).await?;
     Ok(title)
 }
 
+/// 获取视频的分P列表
+pub async fn bv_pages(bv_id: &str) -> Result<Vec<Page>> {
+    let client = reqwest::Client::new();
+    let path = Path::new("load");
+    let cookies = read_cookie_or_not(path).await?;
+    let headers = create_headers(&cookies);
+    let bv = get_bv_cid_title(&client, bv_id, headers).await?;
+    Ok(bv.pages)
+}
+
 pub async fn bv_title(bv_id: &str) -> Result<(String, String)> {
     let client = reqwest::Client::new();
     let path = Path::new("load");
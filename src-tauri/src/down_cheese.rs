@@ -0,0 +1,253 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{read_cookie_or_not, remove_punctuation};
+use crate::down_bv::down_file_bv_;
+use crate::download::{Downloader, TaskContext};
+use crate::refresh_cookie::create_headers;
+use crate::resolution;
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 课程中的一集
+#[derive(Debug, Clone)]
+struct Episode {
+    ep_id: i64,
+    aid: i64,
+    cid: i64,
+    /// 课程内的序号
+    index: i64,
+    title: String,
+    /// 未购买的付费集不能播放
+    locked: bool,
+}
+
+/// 课程信息
+#[derive(Debug)]
+struct Course {
+    title: String,
+    cover: String,
+    episodes: Vec<Episode>,
+}
+
+fn parse_course(data: &Value) -> Course {
+    let episodes = data["episodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|ep| {
+            Some(Episode {
+                ep_id: ep["id"].as_i64()?,
+                aid: ep["aid"].as_i64().unwrap_or(0),
+                cid: ep["cid"].as_i64().unwrap_or(0),
+                index: ep["index"].as_i64().unwrap_or(0),
+                title: remove_punctuation(ep["title"].as_str().unwrap_or("")),
+                // status 1 为可播放（已购买或免费试看），2 为未购买
+                locked: ep["status"].as_i64().unwrap_or(1) != 1,
+            })
+        })
+        .collect();
+    Course {
+        title: remove_punctuation(data["title"].as_str().unwrap_or("no title")),
+        cover: data["cover"].as_str().unwrap_or("").to_string(),
+        episodes,
+    }
+}
+
+/// 课程信息，ep_id 和 season_id 给出一个即可
+async fn get_course(
+    client: &Client,
+    ep_id: &str,
+    season_id: &str,
+    headers: HeaderMap,
+) -> Result<Course> {
+    let url = "https://api.bilibili.com/pugv/view/web/season";
+    let query = if season_id.is_empty() {
+        [("ep_id", ep_id)]
+    } else {
+        [("season_id", season_id)]
+    };
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Failed to parse course info")?;
+    ApiError::check(&json)?;
+    Ok(parse_course(&json["data"]))
+}
+
+/// 课程的播放地址，返回结构与普通视频相同（data.dash）
+async fn get_playurl(
+    client: &Client,
+    episode: &Episode,
+    headers: HeaderMap,
+    rsl: &str,
+) -> Result<Value> {
+    let url = "https://api.bilibili.com/pugv/player/web/playurl";
+    let (avid, cid, ep_id) = (
+        episode.aid.to_string(),
+        episode.cid.to_string(),
+        episode.ep_id.to_string(),
+    );
+    let (qn, fnval) = resolution::playurl_params(rsl);
+    let params: HashMap<&str, &str> = [
+        ("avid", avid.as_str()),
+        ("cid", cid.as_str()),
+        ("ep_id", ep_id.as_str()),
+        ("qn", qn.as_str()),
+        ("fnval", fnval.as_str()),
+        ("fnver", "0"),
+        ("fourk", "1"),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Failed to parse course play url")?;
+    ApiError::check(&json)?;
+    Ok(json)
+}
+
+/// 下载课程：ep 链接只下载该集，ss 链接下载全部已购买的集，未购买的集记为跳过
+pub async fn down_main(
+    (ep_id, season_id): (&str, &str),
+    rsl: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let course = ctx
+        .retry("获取课程信息", || {
+            get_course(&client, ep_id, season_id, headers.clone())
+        })
+        .await?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, course.title.clone())).await;
+    }
+    let selected: Vec<&Episode> = if season_id.is_empty() {
+        let episode = course
+            .episodes
+            .iter()
+            .find(|ep| ep.ep_id.to_string() == ep_id)
+            .context("Episode not found in course")?;
+        if episode.locked {
+            anyhow::bail!("第 {} 集 {} 未购买", episode.index, episode.title);
+        }
+        vec![episode]
+    } else {
+        course.episodes.iter().collect()
+    };
+    let folder = format!("{}/{}", save_path, course.title);
+
+    let downloader = Downloader::new(client.clone(), headers.clone(), ctx);
+    let mut failed = Vec::new();
+    let mut attempted = 0;
+    for episode in selected {
+        if episode.locked {
+            ctx.record_skipped(format!("第 {} 集 {}: 未购买", episode.index, episode.title));
+            continue;
+        }
+        attempted += 1;
+        let result = down_episode(
+            &client,
+            &headers,
+            &downloader,
+            episode,
+            &course,
+            rsl,
+            &folder,
+        )
+        .await;
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("ep{} failed: {:#}", episode.ep_id, e);
+                failed.push(format!(
+                    "第 {} 集 {}: {:#}",
+                    episode.index, episode.title, e
+                ));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 集下载失败\n{}",
+            failed.len(),
+            attempted,
+            failed.join("\n")
+        );
+    }
+    Ok(course.title)
+}
+
+async fn down_episode(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    episode: &Episode,
+    course: &Course,
+    rsl: &str,
+    save_path: &str,
+) -> Result<()> {
+    let play_url = downloader
+        .ctx()
+        .retry("获取播放地址", || {
+            get_playurl(client, episode, headers.clone(), rsl)
+        })
+        .await
+        .context("Failed to get course play url")?;
+    let name = format!("{} {:02} {}", course.title, episode.index, episode.title);
+    down_file_bv_(
+        downloader,
+        play_url,
+        name,
+        rsl,
+        &format!("cheese_ep{}", episode.ep_id),
+        save_path.to_string(),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn course_title(ep_id: &str, season_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let course = get_course(&client, ep_id, season_id, create_headers(&cookies)).await?;
+    Ok((course.title, course.cover))
+}
+
+#[test]
+fn test_parse_course() {
+    let data = serde_json::json!({
+        "title": "Rust 入门", "cover": "http://i0.hdslb.com/c.jpg",
+        "episodes": [
+            { "id": 101, "aid": 1, "cid": 11, "index": 1, "title": "试看", "status": 1 },
+            { "id": 102, "aid": 2, "cid": 12, "index": 2, "title": "付费", "status": 2 },
+        ],
+    });
+    let course = parse_course(&data);
+    assert_eq!(course.episodes.len(), 2);
+    assert!(!course.episodes[0].locked);
+    assert!(course.episodes[1].locked);
+}
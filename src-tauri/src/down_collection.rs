@@ -0,0 +1,182 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{read_cookie_or_not, remove_punctuation};
+use crate::down_bv;
+use crate::download::TaskContext;
+use crate::init_::Pages;
+use crate::refresh_cookie::create_headers;
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 合集中的一个视频
+#[derive(Debug, Clone)]
+struct Episode {
+    bv_id: String,
+    title: String,
+}
+
+/// 合集信息，episodes 已按分节顺序展开
+#[derive(Debug)]
+struct Collection {
+    title: String,
+    cover: String,
+    episodes: Vec<Episode>,
+}
+
+/// 合集视频列表中的第一个视频，用来获取完整的合集信息
+async fn first_bv(
+    client: &Client,
+    mid: &str,
+    collection_id: &str,
+    headers: HeaderMap,
+) -> Result<String> {
+    let url = "https://api.bilibili.com/x/polymer/web-space/seasons_archives_list";
+    let params = [
+        ("mid", mid),
+        ("season_id", collection_id),
+        ("sort_reverse", "false"),
+        ("page_num", "1"),
+        ("page_size", "1"),
+    ];
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    json["data"]["archives"][0]["bvid"]
+        .as_str()
+        .map(|s| s.to_string())
+        .context("Collection is empty")
+}
+
+/// 从视频详情的 ugc_season 中读取合集
+fn parse_collection(view: &Value) -> Result<Collection> {
+    let season = &view["data"]["ugc_season"];
+    if season.is_null() {
+        return Err(anyhow::anyhow!("The video is not part of a collection"));
+    }
+    let episodes = season["sections"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|section| section["episodes"].as_array().into_iter().flatten())
+        .filter_map(|ep| {
+            Some(Episode {
+                bv_id: ep["bvid"].as_str()?.to_string(),
+                title: ep["title"].as_str().unwrap_or("").to_string(),
+            })
+        })
+        .collect();
+    Ok(Collection {
+        title: remove_punctuation(season["title"].as_str().unwrap_or("collection")),
+        cover: season["cover"].as_str().unwrap_or("").to_string(),
+        episodes,
+    })
+}
+
+/// 获取合集：合集链接先找到其中一个视频，BV 链接直接用该视频
+async fn get_collection(
+    client: &Client,
+    (mid, collection_id): (&str, &str),
+    bv_id: &str,
+    headers: HeaderMap,
+) -> Result<Collection> {
+    let bv_id = if bv_id.is_empty() {
+        first_bv(client, mid, collection_id, headers.clone()).await?
+    } else {
+        bv_id.to_string()
+    };
+    let view = down_bv::get_view(client, &bv_id, headers).await?;
+    parse_collection(&view)
+}
+
+/// 按分节顺序下载整个合集，保存到以合集名命名的文件夹
+pub async fn down_main(
+    collection: (&str, &str),
+    bv_id: &str,
+    rsl: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let collection = ctx
+        .retry("获取合集信息", || {
+            get_collection(&client, collection, bv_id, headers.clone())
+        })
+        .await
+        .context("Failed to get collection")?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, collection.title.clone())).await;
+    }
+    let folder = format!("{}/{}", save_path, collection.title);
+    println!(
+        "downloading collection {} ({} videos)",
+        collection.title,
+        collection.episodes.len()
+    );
+
+    // 单个视频失败不影响其余视频，全部尝试后再汇总
+    let mut failed = Vec::new();
+    for episode in &collection.episodes {
+        let result =
+            down_bv::down_main(&episode.bv_id, &Pages::All, rsl, folder.clone(), ctx, None).await;
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("{} failed: {:#}", episode.bv_id, e);
+                failed.push(format!("{} {}: {:#}", episode.bv_id, episode.title, e));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 个视频下载失败\n{}",
+            failed.len(),
+            collection.episodes.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(collection.title)
+}
+
+/// 合集名称和封面
+pub async fn collection_title(collection: (&str, &str), bv_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let collection = get_collection(&client, collection, bv_id, headers).await?;
+    Ok((collection.title, collection.cover))
+}
+
+#[test]
+fn test_parse_collection() {
+    let view = serde_json::json!({ "data": { "ugc_season": {
+        "title": "合集·测试",
+        "cover": "http://i0.hdslb.com/cover.jpg",
+        "sections": [
+            { "title": "第一节", "episodes": [{ "bvid": "BV1a", "title": "一" }, { "bvid": "BV1b", "title": "二" }] },
+            { "title": "第二节", "episodes": [{ "bvid": "BV1c", "title": "三" }] },
+        ],
+    }}});
+    let collection = parse_collection(&view).unwrap();
+    let ids: Vec<&str> = collection
+        .episodes
+        .iter()
+        .map(|e| e.bv_id.as_str())
+        .collect();
+    assert_eq!(ids, ["BV1a", "BV1b", "BV1c"]);
+    assert!(parse_collection(&serde_json::json!({ "data": {} })).is_err());
+}
@@ -0,0 +1,205 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{self, read_cookie_or_not, remove_punctuation};
+use crate::down_bv;
+use crate::download::TaskContext;
+use crate::init_::Pages;
+use crate::refresh_cookie::create_headers;
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 每页条目数，接口上限为 20
+const PAGE_SIZE: &str = "20";
+
+/// 收藏夹中的一项
+#[derive(Debug, Clone, PartialEq)]
+enum FavItem {
+    Video {
+        bv_id: String,
+        title: String,
+    },
+    Bangumi {
+        season_id: String,
+        title: String,
+    },
+    /// 失效、已删除或不支持的条目，附带原因
+    Invalid(String),
+}
+
+/// 获取收藏夹的一页，返回 (收藏夹信息, 条目, 是否还有下一页)
+///
+/// 私密收藏夹需要登录，headers 中带有 `load` 里的 Cookie
+async fn get_page(
+    client: &Client,
+    fav_id: &str,
+    pn: u32,
+    headers: HeaderMap,
+) -> Result<(Value, Vec<FavItem>, bool)> {
+    let url = "https://api.bilibili.com/x/v3/fav/resource/list";
+    let pn = pn.to_string();
+    let params = [
+        ("media_id", fav_id),
+        ("pn", pn.as_str()),
+        ("ps", PAGE_SIZE),
+        ("platform", "web"),
+    ];
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(&params)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    let items = json["data"]["medias"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(parse_item)
+        .collect();
+    let has_more = json["data"]["has_more"].as_bool().unwrap_or(false);
+    Ok((json["data"]["info"].clone(), items, has_more))
+}
+
+/// 解析收藏夹条目：type 2 为视频，24 为番剧/影视；attr 非 0 表示已失效
+fn parse_item(media: &Value) -> FavItem {
+    let title = media["title"].as_str().unwrap_or("").to_string();
+    let bv_id = media["bvid"].as_str().unwrap_or("");
+    if media["attr"].as_i64().unwrap_or(0) != 0 {
+        return FavItem::Invalid(format!("{} {}: 已失效", bv_id, title));
+    }
+    match media["type"].as_i64() {
+        Some(2) if !bv_id.is_empty() => FavItem::Video {
+            bv_id: bv_id.to_string(),
+            title,
+        },
+        Some(24) => match media["ogv"]["season_id"].as_i64() {
+            Some(season_id) => FavItem::Bangumi {
+                season_id: season_id.to_string(),
+                title,
+            },
+            None => FavItem::Invalid(format!("{}: 缺少剧集信息", title)),
+        },
+        kind => FavItem::Invalid(format!("{}: 不支持的类型 {:?}", title, kind)),
+    }
+}
+
+/// 翻页读取收藏夹全部条目，返回收藏夹名称和条目
+async fn get_favlist(
+    client: &Client,
+    fav_id: &str,
+    headers: &HeaderMap,
+    ctx: &TaskContext,
+) -> Result<(String, Vec<FavItem>)> {
+    let mut items = Vec::new();
+    let mut info = Value::Null;
+    for pn in 1.. {
+        let (page_info, page, has_more) = ctx
+            .retry("获取收藏夹", || {
+                get_page(client, fav_id, pn, headers.clone())
+            })
+            .await?;
+        info = page_info;
+        items.extend(page);
+        if !has_more {
+            break;
+        }
+    }
+    let title = remove_punctuation(info["title"].as_str().unwrap_or("favlist"));
+    Ok((title, items))
+}
+
+/// 下载收藏夹中的所有视频和番剧，保存到以收藏夹命名的文件夹
+///
+/// 失效条目记为跳过，单项失败不影响其余条目
+pub async fn down_main(
+    fav_id: &str,
+    rsl: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let (title, items) = get_favlist(&client, fav_id, &headers, ctx)
+        .await
+        .context("Failed to get favorites folder")?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, title.clone())).await;
+    }
+    let folder = format!("{}/{}", save_path, title);
+    println!("downloading favorites {} ({} items)", title, items.len());
+
+    let mut failed = Vec::new();
+    for item in &items {
+        let result = match item {
+            FavItem::Video { bv_id, .. } => {
+                down_bv::down_main(bv_id, &Pages::All, rsl, folder.clone(), ctx, None)
+                    .await
+                    .map(|_| ())
+            }
+            FavItem::Bangumi { season_id, .. } => {
+                down_bangumi::down_main(("", season_id), None, rsl, folder.clone(), ctx, None).await
+            }
+            FavItem::Invalid(reason) => {
+                ctx.record_skipped(reason.clone());
+                continue;
+            }
+        };
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("{:?} failed: {:#}", item, e);
+                failed.push(format!("{:?}: {:#}", item, e));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 项下载失败\n{}",
+            failed.len(),
+            items.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(title)
+}
+
+/// 收藏夹名称和封面
+pub async fn favlist_title(fav_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let (info, _, _) = get_page(&client, fav_id, 1, headers).await?;
+    let title = remove_punctuation(info["title"].as_str().unwrap_or("favlist"));
+    let cover = info["cover"].as_str().unwrap_or("").to_string();
+    Ok((title, cover))
+}
+
+#[test]
+fn test_parse_item() {
+    let video = serde_json::json!({ "type": 2, "attr": 0, "bvid": "BV1a", "title": "视频" });
+    assert_eq!(
+        parse_item(&video),
+        FavItem::Video {
+            bv_id: "BV1a".to_string(),
+            title: "视频".to_string()
+        }
+    );
+    let deleted =
+        serde_json::json!({ "type": 2, "attr": 9, "bvid": "BV1b", "title": "已失效视频" });
+    assert!(matches!(parse_item(&deleted), FavItem::Invalid(_)));
+    let bangumi =
+        serde_json::json!({ "type": 24, "attr": 0, "title": "番剧", "ogv": { "season_id": 123 } });
+    assert!(
+        matches!(parse_item(&bangumi), FavItem::Bangumi { season_id, .. } if season_id == "123")
+    );
+}
@@ -0,0 +1,315 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{append_history, remove_punctuation};
+use crate::down_bv::down_cid;
+use crate::download::Downloader;
+use crate::retry::ApiError;
+use crate::wbi;
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+
+/// 剧情图中的一个选项
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Choice {
+    /// 选项文字
+    pub option: String,
+    /// 选择后进入的节点
+    pub target: i64,
+}
+
+/// 剧情图中的一个节点，对应一段视频
+#[derive(Debug, Clone, Serialize)]
+pub struct Node {
+    pub edge_id: i64,
+    pub cid: i64,
+    pub title: String,
+    /// 下载用的名称（不含清晰度和扩展名），与其他节点同一 cid 时共用
+    pub name: String,
+    /// 下载好的视频文件名，和 graph.json 在同一目录；下载失败时为空
+    pub file: Option<String>,
+    /// 节点结尾的问题，没有选项的是结局
+    pub question: String,
+    pub choices: Vec<Choice>,
+}
+
+/// 导出的互动视频剧情图
+#[derive(Debug, Serialize)]
+pub struct Graph {
+    pub bv_id: String,
+    pub title: String,
+    pub graph_version: i64,
+    /// 起始节点
+    pub root: i64,
+    pub nodes: Vec<Node>,
+}
+
+/// 剧情图版本号，来自播放器信息
+async fn get_graph_version(
+    client: &Client,
+    bv_id: &str,
+    cid: i64,
+    headers: HeaderMap,
+) -> Result<i64> {
+    let query =
+        wbi::sign_query(vec![("bvid", bv_id.to_string()), ("cid", cid.to_string())]).await?;
+    let url = format!("https://api.bilibili.com/x/player/wbi/v2?{}", query);
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    json["data"]["interaction"]["graph_version"]
+        .as_i64()
+        .context("Not an interactive video")
+}
+
+/// 一个节点的信息，edge_id 为 None 时返回起始节点
+async fn get_edge(
+    client: &Client,
+    bv_id: &str,
+    graph_version: i64,
+    edge_id: Option<i64>,
+    headers: HeaderMap,
+) -> Result<Value> {
+    let mut query = vec![
+        ("bvid", bv_id.to_string()),
+        ("graph_version", graph_version.to_string()),
+    ];
+    if let Some(edge_id) = edge_id {
+        query.push(("edge_id", edge_id.to_string()));
+    }
+    let json: Value = client
+        .get("https://api.bilibili.com/x/stein/edgeinfo_v2")
+        .headers(headers)
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    Ok(json["data"].clone())
+}
+
+/// 解析节点信息，cid 在接口中只出现在指向它的选项里，由调用方传入
+fn parse_edge(data: &Value, edge_id: i64, cid: i64) -> Node {
+    let questions = data["edges"]["questions"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let choices = questions
+        .iter()
+        .flat_map(|q| q["choices"].as_array().cloned().unwrap_or_default())
+        .filter_map(|c| {
+            Some(Choice {
+                option: c["option"].as_str().unwrap_or("").to_string(),
+                target: c["id"].as_i64()?,
+            })
+        })
+        .collect();
+    Node {
+        edge_id,
+        cid,
+        title: data["title"].as_str().unwrap_or("").to_string(),
+        name: String::new(),
+        file: None,
+        question: questions
+            .iter()
+            .filter_map(|q| q["title"].as_str())
+            .collect::<Vec<_>>()
+            .join(" / "),
+        choices,
+    }
+}
+
+/// 选项指向的节点及其 cid
+fn choice_cids(data: &Value) -> Vec<(i64, i64)> {
+    data["edges"]["questions"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|q| q["choices"].as_array().into_iter().flatten())
+        .filter_map(|c| Some((c["id"].as_i64()?, c["cid"].as_i64()?)))
+        .collect()
+}
+
+/// 从起始节点广度优先遍历剧情图，收集所有可达节点
+async fn walk_graph(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    bv_id: &str,
+    title: &str,
+    entry_cid: i64,
+) -> Result<Graph> {
+    let ctx = downloader.ctx();
+    let graph_version = ctx
+        .retry("获取剧情图版本", || {
+            get_graph_version(client, bv_id, entry_cid, headers.clone())
+        })
+        .await?;
+    let root = ctx
+        .retry("获取剧情节点", || {
+            get_edge(client, bv_id, graph_version, None, headers.clone())
+        })
+        .await?;
+    let root_id = root["edge_id"].as_i64().unwrap_or(1);
+
+    let mut nodes = Vec::new();
+    let mut seen = HashSet::from([root_id]);
+    let mut queue = VecDeque::from([(root_id, entry_cid, Some(root))]);
+    while let Some((edge_id, cid, data)) = queue.pop_front() {
+        ctx.control.checkpoint().await?;
+        let data = match data {
+            Some(data) => data,
+            None => {
+                ctx.retry("获取剧情节点", || {
+                    get_edge(client, bv_id, graph_version, Some(edge_id), headers.clone())
+                })
+                .await?
+            }
+        };
+        for (target, target_cid) in choice_cids(&data) {
+            if seen.insert(target) {
+                queue.push_back((target, target_cid, None));
+            }
+        }
+        nodes.push(parse_edge(&data, edge_id, cid));
+    }
+    println!("interactive video {} has {} nodes", bv_id, nodes.len());
+    Ok(Graph {
+        bv_id: bv_id.to_string(),
+        title: title.to_string(),
+        graph_version,
+        root: root_id,
+        nodes,
+    })
+}
+
+/// 给节点编文件名，同一 cid 只下载一次
+fn assign_names(graph: &mut Graph) -> Vec<(i64, String)> {
+    let mut files: Vec<(i64, String)> = Vec::new();
+    for node in &mut graph.nodes {
+        if let Some((_, name)) = files.iter().find(|(cid, _)| *cid == node.cid) {
+            node.name = name.clone();
+            continue;
+        }
+        let title = remove_punctuation(&node.title);
+        node.name = format!("{:03} {}", files.len() + 1, title)
+            .trim()
+            .to_string();
+        files.push((node.cid, node.name.clone()));
+    }
+    files
+}
+
+/// 下载互动视频的所有节点到以视频命名的文件夹，并导出剧情图 graph.json
+pub async fn down_main(
+    client: &Client,
+    headers: &HeaderMap,
+    downloader: &Downloader,
+    (bv_id, title, entry_cid): (&str, &str, i64),
+    rsl: &str,
+    save_path: &str,
+) -> Result<()> {
+    let mut graph = walk_graph(client, headers, downloader, bv_id, title, entry_cid).await?;
+    let files = assign_names(&mut graph);
+    let folder = format!("{}/{}", save_path, title);
+    std::fs::create_dir_all(&folder)?;
+
+    let mut failed = Vec::new();
+    let mut cancelled = None;
+    for (cid, name) in &files {
+        let result = down_cid(
+            client,
+            headers,
+            downloader,
+            (bv_id, *cid, &format!("{}_{}", bv_id, cid)),
+            name.clone(),
+            rsl,
+            &folder,
+        )
+        .await;
+        match result {
+            Ok(file) => set_file(&mut graph, *cid, file),
+            Err(e) if e.is::<Cancelled>() => {
+                cancelled = Some(e);
+                break;
+            }
+            Err(e) => {
+                println!("{} failed: {:#}", name, e);
+                failed.push(format!("{}: {:#}", name, e));
+            }
+        }
+    }
+    // 取消或部分失败时也导出已下载的节点
+    let json = serde_json::to_string_pretty(&graph)?;
+    std::fs::write(format!("{}/graph.json", folder), json).context("Failed to write graph.json")?;
+    if let Some(e) = cancelled {
+        return Err(e);
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 个剧情节点下载失败\n{}",
+            failed.len(),
+            files.len(),
+            failed.join("\n")
+        );
+    }
+    // 所有节点都下载完成后才记录整个视频
+    append_history(bv_id, title).await
+}
+
+/// 记录 cid 对应的视频文件，共用这个 cid 的节点都指向它
+fn set_file(graph: &mut Graph, cid: i64, file: String) {
+    for node in graph.nodes.iter_mut().filter(|n| n.cid == cid) {
+        node.file = Some(file.clone());
+    }
+}
+
+#[test]
+fn test_parse_edge() {
+    let data = serde_json::json!({
+        "title": "开始", "edge_id": 1,
+        "edges": { "questions": [{ "title": "去哪里？", "choices": [
+            { "id": 2, "cid": 200, "option": "左边" },
+            { "id": 3, "cid": 300, "option": "右边" },
+        ]}]},
+    });
+    let node = parse_edge(&data, 1, 100);
+    assert_eq!(node.question, "去哪里？");
+    assert_eq!(
+        node.choices[1],
+        Choice {
+            option: "右边".to_string(),
+            target: 3
+        }
+    );
+    assert_eq!(choice_cids(&data), vec![(2, 200), (3, 300)]);
+
+    let leaf = |edge_id, cid| parse_edge(&serde_json::json!({ "title": "结局" }), edge_id, cid);
+    let mut graph = Graph {
+        bv_id: "BV1a".to_string(),
+        title: "t".to_string(),
+        graph_version: 1,
+        root: 1,
+        nodes: vec![node, leaf(2, 200), leaf(3, 200)],
+    };
+    let files = assign_names(&mut graph);
+    assert_eq!(files.len(), 2);
+    assert_eq!(graph.nodes[2].name, "002 结局");
+    assert!(graph.nodes[1].choices.is_empty());
+    set_file(&mut graph, 200, "002 结局 1080P AVC.mp4".to_string());
+    assert_eq!(
+        graph.nodes[2].file.as_deref(),
+        Some("002 结局 1080P AVC.mp4")
+    );
+    assert!(graph.nodes[0].file.is_none());
+}
@@ -0,0 +1,334 @@
+use crate::control::Cancelled;
+use crate::down_bangumi::{read_cookie_or_not, remove_punctuation};
+use crate::down_bv;
+use crate::download::TaskContext;
+use crate::init_::Pages;
+use crate::refresh_cookie::create_headers;
+use crate::retry::ApiError;
+use crate::wbi;
+use anyhow::{Context, Result};
+use chrono::{FixedOffset, NaiveDate, TimeZone};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 每页视频数，接口上限为 50
+const PAGE_SIZE: u32 = 30;
+
+/// 网页端投稿列表请求带的 WebGL 指纹，缺少时接口返回 -352（风控校验失败）。
+/// 取值与浏览器一致：WebGL 版本 `WebGL 1.0 (OpenGL ES 2.0 Chromium)` 的 base64 去掉末尾的 `==`
+const DM_IMG_STR: &str = "V2ViR0wgMS4wIChPcGVuR0wgRVMgMi4wIENocm9taXVtKQ";
+/// 显卡渲染器 `ANGLE (NVIDIA, NVIDIA GeForce GTX 1060 6GB Direct3D11 vs_5_0 ps_5_0), Google Inc. (NVIDIA)` 的 base64
+const DM_COVER_IMG_STR: &str = "QU5HTEUgKE5WSURJQSwgTlZJRElBIEdlRm9yY2UgR1RYIDEwNjAgNkdCIERpcmVjdDNEMTEgdnNfNV8wIHBzXzVfMCksIEdvb2dsZSBJbmMuIChOVklESUEp";
+
+/// UP 主投稿的筛选条件，来自空间链接的查询参数
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpaceFilter {
+    /// 发布时间下限（含），北京时间的 unix 秒
+    pub from: Option<i64>,
+    /// 发布时间上限（不含），北京时间的 unix 秒
+    pub to: Option<i64>,
+    /// 标题关键词，空为不限
+    pub keyword: String,
+    /// 最多下载的视频数，不含下载历史中已有的
+    pub max_count: Option<usize>,
+}
+
+impl SpaceFilter {
+    /// 解析 `from`、`to`（`YYYY-MM-DD`，均包含当天）、`keyword` 和 `max` 参数
+    pub fn parse(
+        from: Option<&str>,
+        to: Option<&str>,
+        keyword: Option<&str>,
+        max: Option<&str>,
+    ) -> Result<Self> {
+        let day_start = |date: &str, offset_days: i64| -> Result<i64> {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .with_context(|| format!("Invalid date: {}", date))?;
+            let start = date.and_hms_opt(0, 0, 0).context("Invalid date")?;
+            let tz = FixedOffset::east_opt(8 * 3600).context("Invalid timezone")?;
+            let start = tz
+                .from_local_datetime(&start)
+                .single()
+                .context("Invalid date")?;
+            Ok(start.timestamp() + offset_days * 86400)
+        };
+        Ok(Self {
+            from: from.map(|d| day_start(d, 0)).transpose()?,
+            to: to.map(|d| day_start(d, 1)).transpose()?,
+            keyword: keyword.unwrap_or("").trim().to_string(),
+            max_count: max
+                .map(|m| m.parse::<usize>())
+                .transpose()
+                .context("Invalid max")?,
+        })
+    }
+
+    fn is_full(&self, matched: &[SpaceVideo]) -> bool {
+        self.max_count.is_some_and(|max| matched.len() >= max)
+    }
+
+    fn matches(&self, video: &SpaceVideo) -> bool {
+        self.from.is_none_or(|from| video.created >= from)
+            && self.to.is_none_or(|to| video.created < to)
+            && (self.keyword.is_empty()
+                || video
+                    .title
+                    .to_lowercase()
+                    .contains(&self.keyword.to_lowercase()))
+    }
+}
+
+/// UP 主的一个投稿
+#[derive(Debug, Clone)]
+struct SpaceVideo {
+    bv_id: String,
+    title: String,
+    /// 发布时间 unix 秒
+    created: i64,
+}
+
+/// 获取投稿列表的一页（按发布时间倒序），返回视频和投稿总数
+async fn get_page(
+    client: &Client,
+    mid: &str,
+    keyword: &str,
+    pn: u32,
+    headers: HeaderMap,
+) -> Result<(Vec<SpaceVideo>, u64)> {
+    let params = vec![
+        ("mid", mid.to_string()),
+        ("ps", PAGE_SIZE.to_string()),
+        ("pn", pn.to_string()),
+        ("order", "pubdate".to_string()),
+        ("keyword", keyword.to_string()),
+        ("platform", "web".to_string()),
+        ("dm_img_list", "[]".to_string()),
+        ("dm_img_str", DM_IMG_STR.to_string()),
+        ("dm_cover_img_str", DM_COVER_IMG_STR.to_string()),
+    ];
+    let query = wbi::sign_query(params).await?;
+    let url = format!("https://api.bilibili.com/x/space/wbi/arc/search?{}", query);
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    let videos = json["data"]["list"]["vlist"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| {
+            Some(SpaceVideo {
+                bv_id: v["bvid"].as_str()?.to_string(),
+                title: v["title"].as_str().unwrap_or("").to_string(),
+                created: v["created"].as_i64().unwrap_or(0),
+            })
+        })
+        .collect();
+    let count = json["data"]["page"]["count"].as_u64().unwrap_or(0);
+    Ok((videos, count))
+}
+
+/// 把一页中符合条件的投稿分到待下载和已下载两组，已下载的不计入 max_count
+fn collect_page(
+    filter: &SpaceFilter,
+    history: &HashSet<String>,
+    videos: Vec<SpaceVideo>,
+    matched: &mut Vec<SpaceVideo>,
+    downloaded: &mut Vec<SpaceVideo>,
+) {
+    for video in videos.into_iter().filter(|v| filter.matches(v)) {
+        if filter.is_full(matched) {
+            break;
+        }
+        if history.contains(&video.bv_id) {
+            downloaded.push(video);
+        } else {
+            matched.push(video);
+        }
+    }
+}
+
+/// 翻页读取符合条件的投稿，返回要下载的（最多 max_count 个）和下载历史中已有的
+async fn list_videos(
+    client: &Client,
+    mid: &str,
+    filter: &SpaceFilter,
+    history: &HashSet<String>,
+    headers: &HeaderMap,
+    ctx: &TaskContext,
+) -> Result<(Vec<SpaceVideo>, Vec<SpaceVideo>)> {
+    let mut matched = Vec::new();
+    let mut downloaded = Vec::new();
+    for pn in 1.. {
+        // 凑满后不再翻页
+        if filter.is_full(&matched) {
+            break;
+        }
+        let (videos, count) = ctx
+            .retry("获取投稿列表", || {
+                get_page(client, mid, &filter.keyword, pn, headers.clone())
+            })
+            .await?;
+        let last_page = videos.is_empty() || (pn * PAGE_SIZE) as u64 >= count;
+        // 按发布时间倒序，早于下限之后的都不用再看
+        let too_old = videos
+            .last()
+            .is_some_and(|v| filter.from.is_some_and(|from| v.created < from));
+        collect_page(filter, history, videos, &mut matched, &mut downloaded);
+        if last_page || too_old {
+            break;
+        }
+    }
+    Ok((matched, downloaded))
+}
+
+/// 下载历史 dat.log 中已完整下载的 BV 号
+fn history_bv_ids() -> HashSet<String> {
+    complete_bv_ids(&std::fs::read_to_string("dat.log").unwrap_or_default())
+}
+
+fn complete_bv_ids(history: &str) -> HashSet<String> {
+    history
+        .lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        // 多P视频的单P记录为 `{bv}_p{n}`，全部分P完成后才有整个视频的记录
+        .filter(|id| id.starts_with("BV") && id.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// 下载 UP 主的投稿，保存到以 UP 主命名的文件夹；历史记录中已有的视频跳过
+pub async fn down_main(
+    mid: &str,
+    filter: &SpaceFilter,
+    rsl: &str,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let (name, _) = ctx
+        .retry("获取 UP 主信息", || {
+            get_space_info(&client, mid, headers.clone())
+        })
+        .await?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, name.clone())).await;
+    }
+    let history = history_bv_ids();
+    let (videos, downloaded) = list_videos(&client, mid, filter, &history, &headers, ctx)
+        .await
+        .context("Failed to list uploads")?;
+    for video in &downloaded {
+        ctx.record_skipped(format!("{} {}: 已在下载历史中", video.bv_id, video.title));
+    }
+    let folder = format!("{}/{}", save_path, name);
+    println!("downloading {} uploads of {}", videos.len(), name);
+
+    let mut failed = Vec::new();
+    for video in &videos {
+        let result =
+            down_bv::down_main(&video.bv_id, &Pages::All, rsl, folder.clone(), ctx, None).await;
+        match result {
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                println!("{} failed: {:#}", video.bv_id, e);
+                failed.push(format!("{} {}: {:#}", video.bv_id, video.title, e));
+            }
+            _ => {}
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{}/{} 个视频下载失败\n{}",
+            failed.len(),
+            videos.len(),
+            failed.join("\n")
+        );
+    }
+    Ok(name)
+}
+
+/// UP 主名称和头像
+async fn get_space_info(
+    client: &Client,
+    mid: &str,
+    headers: HeaderMap,
+) -> Result<(String, String)> {
+    let url = "https://api.bilibili.com/x/web-interface/card";
+    let json: Value = client
+        .get(url)
+        .headers(headers)
+        .query(&[("mid", mid)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    let name = remove_punctuation(json["data"]["card"]["name"].as_str().unwrap_or(mid));
+    let face = json["data"]["card"]["face"]
+        .as_str()
+        .unwrap_or("")
+        .to_string();
+    Ok((name, face))
+}
+
+pub async fn space_title(mid: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    get_space_info(&client, mid, headers).await
+}
+
+#[test]
+fn test_space_filter() {
+    let filter =
+        SpaceFilter::parse(Some("2024-01-01"), Some("2024-01-31"), Some("Rust"), None).unwrap();
+    let video = |title: &str, created: i64| SpaceVideo {
+        bv_id: "BV1a".to_string(),
+        title: title.to_string(),
+        created,
+    };
+    // 2024-01-01 00:00:00 +08:00
+    let jan1 = 1704038400;
+    assert!(filter.matches(&video("学习 rust", jan1)));
+    assert!(!filter.matches(&video("学习 rust", jan1 - 1)));
+    assert!(filter.matches(&video("rust", jan1 + 31 * 86400 - 1)));
+    assert!(!filter.matches(&video("rust", jan1 + 31 * 86400)));
+    assert!(!filter.matches(&video("go", jan1)));
+    assert!(SpaceFilter::parse(Some("2024/01/01"), None, None, None).is_err());
+
+    let filter = SpaceFilter::parse(None, None, None, Some("2")).unwrap();
+    let page = (1..=4)
+        .map(|i| SpaceVideo {
+            bv_id: format!("BV{}", i),
+            title: String::new(),
+            created: 0,
+        })
+        .collect();
+    let history = HashSet::from(["BV1".to_string(), "BV2".to_string()]);
+    let (mut matched, mut downloaded) = (Vec::new(), Vec::new());
+    collect_page(&filter, &history, page, &mut matched, &mut downloaded);
+    let ids = |v: &[SpaceVideo]| v.iter().map(|v| v.bv_id.clone()).collect::<Vec<_>>();
+    assert_eq!(ids(&matched), ["BV3", "BV4"]);
+    assert_eq!(ids(&downloaded), ["BV1", "BV2"]);
+    assert!(filter.is_full(&matched));
+
+    let history = "t\tBV1a\tA\t\nt\tBV1b_p1\tB P1\t\nt\tep12\tC\t\n";
+    assert_eq!(
+        complete_bv_ids(history),
+        HashSet::from(["BV1a".to_string()])
+    );
+}
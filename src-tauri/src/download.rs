@@ -0,0 +1,716 @@
+use crate::control::{Cancelled, Interrupted, TaskControl};
+use crate::progress::DownloadProgress;
+use crate::ratelimit::RateLimiter;
+use crate::resolution::CodecPreference;
+use crate::resume::{self, Segment};
+use crate::retry::{self, RetryPolicy};
+use crate::verify::{self, VerifyError};
+use anyhow::{Context, Result};
+use futures_util::TryStreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::{Client, Response, StatusCode};
+use std::fmt;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// 进度事件的最小间隔
+const EMIT_INTERVAL: Duration = Duration::from_millis(200);
+/// 续传状态的保存间隔
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// 等待响应头的超时
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+/// 两个数据块之间的最长间隔，超过视为连接卡住
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// 写入缓冲区大小
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+/// 分段下载时每累计这么多字节落盘并上报一次
+const SEGMENT_FLUSH_SIZE: u64 = 256 * 1024;
+/// 每段最小字节数，太小的流不值得分段
+const MIN_SEGMENT_SIZE: u64 = 2 * 1024 * 1024;
+
+/// 流在收到全部数据前结束
+#[derive(Debug)]
+pub struct StreamEnded {
+    pub downloaded: u64,
+    pub total: u64,
+}
+
+impl fmt::Display for StreamEnded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Stream ended early: {}/{} bytes",
+            self.downloaded, self.total
+        )
+    }
+}
+
+impl std::error::Error for StreamEnded {}
+
+/// 待下载的流
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    /// 主地址在前，备用地址在后
+    pub urls: Vec<String>,
+    pub path: String,
+    /// 接口给出的文件大小，下载后用于校验
+    pub size: Option<u64>,
+}
+
+/// 单个下载任务的上下文，由队列创建并传给各类视频的下载流程
+#[derive(Clone)]
+pub struct TaskContext {
+    /// 单个流的下载连接数
+    pub connections: usize,
+    pub control: TaskControl,
+    pub progress_tx: Option<mpsc::Sender<DownloadProgress>>,
+    /// 实际完成下载的 CDN 主机，随任务结果返回
+    hosts: Arc<Mutex<Vec<String>>>,
+    /// 批量下载中被跳过的条目及原因，随任务结果返回
+    skipped: Arc<Mutex<Vec<String>>>,
+    /// 写入前依次获取令牌的限速器（全局、本任务）
+    limiters: Vec<Arc<RateLimiter>>,
+    retry: RetryPolicy,
+    /// 视频编码偏好
+    pub codec: CodecPreference,
+    /// 实际下载的视频编码，随任务结果返回
+    codecs: Arc<Mutex<Vec<String>>>,
+}
+
+impl TaskContext {
+    pub fn new(
+        connections: usize,
+        control: TaskControl,
+        progress_tx: Option<mpsc::Sender<DownloadProgress>>,
+        limiters: Vec<Arc<RateLimiter>>,
+        retry: RetryPolicy,
+        codec: CodecPreference,
+    ) -> Self {
+        Self {
+            connections,
+            control,
+            progress_tx,
+            hosts: Arc::default(),
+            skipped: Arc::default(),
+            limiters,
+            retry,
+            codec,
+            codecs: Arc::default(),
+        }
+    }
+
+    /// 按重试策略执行，临时错误会退避后重试
+    pub async fn retry<T, F, Fut>(&self, what: &str, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry
+            .run(what, &self.control, retry::is_transient, f)
+            .await
+    }
+
+    /// 只在校验失败时重试，用于下载后合并、校验整个流程
+    pub async fn retry_verify<T, F, Fut>(&self, what: &str, f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.retry
+            .run(what, &self.control, verify::is_verify_error, f)
+            .await
+    }
+
+    /// 按限速等待，之后才能写入 n 个字节
+    async fn throttle(&self, n: usize) {
+        for limiter in &self.limiters {
+            limiter.acquire(n as u64).await;
+        }
+    }
+
+    /// 本任务各个流最终使用的主机（去重）
+    pub fn hosts(&self) -> Vec<String> {
+        self.hosts.lock().map(|h| h.clone()).unwrap_or_default()
+    }
+
+    /// 批量下载中被跳过的条目
+    pub fn skipped(&self) -> Vec<String> {
+        self.skipped.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    pub fn record_skipped(&self, item: String) {
+        println!("skipped {}", item);
+        if let Ok(mut skipped) = self.skipped.lock() {
+            skipped.push(item);
+        }
+    }
+
+    /// 本任务下载的视频编码（去重）
+    pub fn codecs(&self) -> Vec<String> {
+        self.codecs.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    pub fn record_codec(&self, codec: &str) {
+        if let Ok(mut codecs) = self.codecs.lock() {
+            if !codec.is_empty() && !codecs.iter().any(|c| c == codec) {
+                codecs.push(codec.to_string());
+            }
+        }
+    }
+
+    fn record_host(&self, url: &str) {
+        if let Ok(mut hosts) = self.hosts.lock() {
+            let host = host(url);
+            if !hosts.iter().any(|h| h == host) {
+                hosts.push(host.to_string());
+            }
+        }
+    }
+}
+
+/// 流下载引擎：断点续传、多连接分段、暂停/取消、进度上报都在这里处理
+#[derive(Clone)]
+pub struct Downloader {
+    client: Client,
+    headers: HeaderMap,
+    ctx: TaskContext,
+}
+
+impl Downloader {
+    pub fn new(client: Client, headers: HeaderMap, ctx: &TaskContext) -> Self {
+        Self {
+            client,
+            headers,
+            ctx: ctx.clone(),
+        }
+    }
+
+    pub fn control(&self) -> &TaskControl {
+        &self.ctx.control
+    }
+
+    pub fn ctx(&self) -> &TaskContext {
+        &self.ctx
+    }
+
+    /// 依次下载一组流，已完成的跳过；失败时按取消设置清理临时文件
+    ///
+    /// 地址列表按主地址、备用地址排列，当前地址出错时换下一个继续。
+    /// 完成后校验文件大小，不一致时删除文件并返回 [`VerifyError`]
+    pub async fn fetch_streams(&self, streams: &[Stream]) -> Result<()> {
+        let paths: Vec<&str> = streams.iter().map(|s| s.path.as_str()).collect();
+        let file_count = streams.len() as u32;
+        for (file_index, stream) in streams.iter().enumerate() {
+            let (urls, path) = (&stream.urls, stream.path.as_str());
+            if resume::is_finished(path) {
+                println!("{} already downloaded", path);
+            } else {
+                let (index, control) = (file_index as u32, &self.ctx.control);
+                let result = self
+                    .ctx
+                    .retry(&format!("下载 {}", path), move || {
+                        control.run(move || self.fetch_any(urls, path, index, file_count))
+                    })
+                    .await;
+                if let Err(e) = result {
+                    self.cleanup(&paths);
+                    return Err(e);
+                }
+            }
+            verify::check_size(path, stream.size)?;
+        }
+        Ok(())
+    }
+
+    /// 依次尝试各个地址，续传状态与主机无关，换地址后从已下载的位置继续
+    async fn fetch_any(
+        &self,
+        urls: &[String],
+        path: &str,
+        file_index: u32,
+        file_count: u32,
+    ) -> Result<()> {
+        let mut last_error = None;
+        for (i, url) in urls.iter().enumerate() {
+            match self.fetch(url, path, file_index, file_count).await {
+                Ok(()) => {
+                    self.ctx.record_host(url);
+                    return Ok(());
+                }
+                Err(e) if is_cdn_error(&e) && i + 1 < urls.len() => {
+                    println!(
+                        "{} failed on {}: {:#}, switching to {}",
+                        path,
+                        host(url),
+                        e,
+                        host(&urls[i + 1])
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No stream url for {}", path)))
+    }
+
+    /// 取消且不保留文件时删除临时文件
+    pub fn cleanup(&self, paths: &[&str]) {
+        self.ctx.control.cleanup(paths);
+    }
+
+    /// 下载单个流，优先沿用续传状态
+    async fn fetch(&self, url: &str, path: &str, file_index: u32, file_count: u32) -> Result<()> {
+        let mut progress = Progress::new(self.ctx.progress_tx.clone(), file_index, file_count);
+        let state = resume::load(path, url);
+        if let Some(state) = state.as_ref().filter(|s| !s.segments.is_empty()) {
+            // 上次是分段下载，沿用原来的分段继续
+            return self
+                .fetch_segmented(
+                    url,
+                    path,
+                    state.total,
+                    state.segments.clone(),
+                    &mut progress,
+                )
+                .await;
+        }
+
+        let offset = state.map(|s| s.downloaded).unwrap_or(0);
+        // 多连接时也带上 Range，借此探测服务器是否支持分段
+        let range = (offset > 0 || self.ctx.connections > 1).then(|| format!("bytes={}-", offset));
+        let resp = self.request(url, range).await?;
+        // 服务器不支持 Range 时返回 200，只能从头开始
+        let ranged = resp.status() == StatusCode::PARTIAL_CONTENT;
+        let offset = if ranged { offset } else { 0 };
+        let total_size = offset + resp.content_length().unwrap_or(0);
+
+        if ranged && should_split(offset, total_size, self.ctx.connections) {
+            drop(resp);
+            let segments = split_ranges(offset, total_size, self.ctx.connections);
+            return self
+                .fetch_segmented(url, path, total_size, segments, &mut progress)
+                .await;
+        }
+        self.fetch_single(url, path, resp, offset, total_size, &mut progress)
+            .await
+    }
+
+    async fn request(&self, url: &str, range: Option<String>) -> Result<Response> {
+        let mut req = self.client.get(url).headers(self.headers.clone());
+        if let Some(range) = range {
+            req = req.header(RANGE, range);
+        }
+        let resp = timeout(RESPONSE_TIMEOUT, req.send())
+            .await
+            .with_context(|| format!("Timed out waiting for {}", host(url)))?
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to download stream from {}", host(url)))?;
+        Ok(resp)
+    }
+
+    /// 单连接下载，从 offset 处追加写入 `.part`
+    async fn fetch_single(
+        &self,
+        url: &str,
+        path: &str,
+        resp: Response,
+        offset: u64,
+        total_size: u64,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        let part = resume::part_path(path);
+        let file = if offset > 0 {
+            println!("resume {} from {} bytes", path, offset);
+            OpenOptions::new().append(true).open(&part).await?
+        } else {
+            File::create(&part).await?
+        };
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+        let mut stream = resp.bytes_stream();
+        let mut downloaded = offset;
+        let mut last_save = Instant::now();
+        progress.start(downloaded, total_size);
+
+        loop {
+            // 限速等待也要能被暂停/取消打断
+            let next = async {
+                let chunk = next_chunk(&mut stream).await?;
+                if let Some(chunk) = &chunk {
+                    self.ctx.throttle(chunk.len()).await;
+                }
+                Ok::<_, anyhow::Error>(chunk)
+            };
+            let chunk = tokio::select! {
+                chunk = next => chunk?,
+                _ = self.ctx.control.interrupted() => {
+                    file.flush().await?;
+                    resume::save(path, url, total_size, downloaded)?;
+                    return Err(Interrupted.into());
+                }
+            };
+            let Some(chunk) = chunk else { break };
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                // 先落盘再记录，保证续传状态不超前于文件内容
+                file.flush().await?;
+                resume::save(path, url, total_size, downloaded)?;
+                last_save = Instant::now();
+            }
+            progress.update(downloaded).await;
+        }
+        file.flush().await?;
+        if total_size > 0 && downloaded > total_size {
+            drop(file);
+            resume::discard(path);
+            return Err(VerifyError(format!(
+                "{} 收到 {} 字节，超过 Content-Length {}",
+                path, downloaded, total_size
+            ))
+            .into());
+        }
+        if downloaded < total_size {
+            resume::save(path, url, total_size, downloaded)?;
+            return Err(StreamEnded {
+                downloaded,
+                total: total_size,
+            }
+            .into());
+        }
+        drop(file);
+        resume::finish(path)?;
+        progress.finish(downloaded).await;
+        Ok(())
+    }
+
+    /// 多连接分段下载，各段写入预分配文件的对应位置，合并上报进度
+    async fn fetch_segmented(
+        &self,
+        url: &str,
+        path: &str,
+        total_size: u64,
+        mut segments: Vec<Segment>,
+        progress: &mut Progress,
+    ) -> Result<()> {
+        let part = resume::part_path(path);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&part)
+            .await?;
+        file.set_len(total_size).await?;
+        drop(file);
+
+        let mut downloaded: u64 = segments.iter().map(|s| s.pos - s.start).sum();
+        println!(
+            "downloading {} with {} connections",
+            path,
+            segments.iter().filter(|s| !s.is_done()).count()
+        );
+        progress.start(downloaded, total_size);
+
+        let (tx, mut rx) = mpsc::channel::<(usize, u64)>(256);
+        let mut tasks = JoinSet::new();
+        for (index, segment) in segments.iter().enumerate() {
+            if segment.is_done() {
+                continue;
+            }
+            tasks.spawn(self.clone().fetch_range(
+                url.to_string(),
+                part.clone(),
+                index,
+                segment.clone(),
+                tx.clone(),
+            ));
+        }
+        drop(tx);
+
+        let mut last_save = Instant::now();
+        let mut failure: Option<anyhow::Error> = None;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some((index, len)) => {
+                        segments[index].pos += len;
+                        downloaded += len;
+                    }
+                    None => break,
+                },
+                Some(result) = tasks.join_next() => {
+                    if let Err(e) = result.context("Segment task panicked").and_then(|r| r) {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+                _ = self.ctx.control.interrupted() => {
+                    failure = Some(Interrupted.into());
+                    break;
+                }
+            }
+
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                resume::save_segments(path, url, total_size, downloaded, segments.clone())?;
+                last_save = Instant::now();
+            }
+            progress.update(downloaded).await;
+        }
+
+        // 所有分段的通道都已关闭，收集剩余任务的结果
+        if failure.is_none() {
+            while let Some(result) = tasks.join_next().await {
+                if let Err(e) = result.context("Segment task panicked").and_then(|r| r) {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+        if let Some(e) = failure {
+            tasks.abort_all();
+            // 已上报的写入都已落盘，保留进度以便续传
+            while let Ok((index, len)) = rx.try_recv() {
+                segments[index].pos += len;
+                downloaded += len;
+            }
+            resume::save_segments(path, url, total_size, downloaded, segments)?;
+            return Err(e);
+        }
+        if segments.iter().any(|s| !s.is_done()) {
+            resume::save_segments(path, url, total_size, downloaded, segments)?;
+            return Err(StreamEnded {
+                downloaded,
+                total: total_size,
+            }
+            .into());
+        }
+        resume::finish(path)?;
+        progress.finish(downloaded).await;
+        Ok(())
+    }
+
+    /// 下载一段并写入文件对应位置，落盘后再上报已写入的字节数
+    async fn fetch_range(
+        self,
+        url: String,
+        part: String,
+        index: usize,
+        segment: Segment,
+        tx: mpsc::Sender<(usize, u64)>,
+    ) -> Result<()> {
+        let range = format!("bytes={}-{}", segment.pos, segment.end - 1);
+        let resp = self.request(&url, Some(range)).await?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(anyhow::anyhow!("Server ignored Range request"));
+        }
+        let mut file = OpenOptions::new().write(true).open(&part).await?;
+        file.seek(SeekFrom::Start(segment.pos)).await?;
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
+        let mut stream = resp.bytes_stream();
+        let mut pos = segment.pos;
+        let mut unreported: u64 = 0;
+        while let Some(chunk) = next_chunk(&mut stream).await? {
+            let len = (chunk.len() as u64).min(segment.end - pos);
+            self.ctx.throttle(len as usize).await;
+            file.write_all(&chunk[..len as usize]).await?;
+            pos += len;
+            unreported += len;
+            if unreported >= SEGMENT_FLUSH_SIZE || pos >= segment.end {
+                file.flush().await?;
+                let _ = tx.send((index, unreported)).await;
+                unreported = 0;
+            }
+            if pos >= segment.end {
+                break;
+            }
+        }
+        if pos < segment.end {
+            file.flush().await?;
+            let _ = tx.send((index, unreported)).await;
+            return Err(StreamEnded {
+                downloaded: pos - segment.start,
+                total: segment.end - segment.start,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// 读取下一个数据块，长时间没有数据时返回错误
+pub async fn next_chunk<S, T>(stream: &mut S) -> Result<Option<T>>
+where
+    S: futures_util::Stream<Item = reqwest::Result<T>> + Unpin,
+{
+    let chunk = timeout(STALL_TIMEOUT, stream.try_next())
+        .await
+        .context("Stream stalled")??;
+    Ok(chunk)
+}
+
+/// 是否为换一个 CDN 可能解决的错误（HTTP 状态、超时、连接中断、流提前结束等）
+///
+/// 暂停/取消和本地文件读写失败换地址也无济于事
+fn is_cdn_error(e: &anyhow::Error) -> bool {
+    if e.is::<Interrupted>() || e.is::<Cancelled>() {
+        return false;
+    }
+    let network = e.chain().any(|c| c.is::<reqwest::Error>());
+    network || !e.root_cause().is::<std::io::Error>()
+}
+
+/// 用于错误信息和结果记录的主机名
+fn host(url: &str) -> &str {
+    url.split("://")
+        .nth(1)
+        .and_then(|s| s.split('/').next())
+        .unwrap_or(url)
+}
+
+/// 剩余部分 `[offset, total)` 是否值得分段下载
+fn should_split(offset: u64, total: u64, connections: usize) -> bool {
+    connections > 1 && total.saturating_sub(offset) >= 2 * MIN_SEGMENT_SIZE
+}
+
+/// 将 `[offset, total)` 按连接数切分，`[0, offset)` 视为已下载
+fn split_ranges(offset: u64, total: u64, connections: usize) -> Vec<Segment> {
+    let remaining = total.saturating_sub(offset);
+    let count = (remaining / MIN_SEGMENT_SIZE).clamp(1, connections.max(1) as u64);
+    let size = remaining.div_ceil(count);
+    let mut segments = Vec::new();
+    if offset > 0 {
+        segments.push(Segment {
+            start: 0,
+            end: offset,
+            pos: offset,
+        });
+    }
+    let mut start = offset;
+    while start < total {
+        let end = (start + size).min(total);
+        segments.push(Segment {
+            start,
+            end,
+            pos: start,
+        });
+        start = end;
+    }
+    segments
+}
+
+/// 控制台进度条与前端进度事件，统一计算速度与剩余时间并节流
+pub struct Progress {
+    tx: Option<mpsc::Sender<DownloadProgress>>,
+    file_index: u32,
+    file_count: u32,
+    total: u64,
+    pb: ProgressBar,
+    last_emit: Instant,
+    last_downloaded: u64,
+}
+
+impl Progress {
+    pub fn new(
+        tx: Option<mpsc::Sender<DownloadProgress>>,
+        file_index: u32,
+        file_count: u32,
+    ) -> Self {
+        Self {
+            tx,
+            file_index,
+            file_count,
+            total: 0,
+            pb: ProgressBar::hidden(),
+            last_emit: Instant::now(),
+            last_downloaded: 0,
+        }
+    }
+
+    pub fn start(&mut self, downloaded: u64, total: u64) {
+        self.total = total;
+        self.pb = ProgressBar::new(total);
+        if let Ok(style) = ProgressStyle::default_bar().template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        ) {
+            self.pb.set_style(style.progress_chars("=> "));
+        }
+        self.pb.set_position(downloaded);
+        self.last_emit = Instant::now();
+        self.last_downloaded = downloaded;
+    }
+
+    pub async fn update(&mut self, downloaded: u64) {
+        self.pb.set_position(downloaded);
+        if self.last_emit.elapsed() >= EMIT_INTERVAL {
+            self.emit(downloaded).await;
+        }
+    }
+
+    pub async fn finish(&mut self, downloaded: u64) {
+        self.emit(downloaded).await;
+        self.pb.finish_with_message("Downloaded stream");
+    }
+
+    async fn emit(&mut self, downloaded: u64) {
+        let Some(tx) = &self.tx else { return };
+        let total = self.total;
+        let elapsed_secs = self.last_emit.elapsed().as_secs_f64().max(0.001);
+        let speed = downloaded.saturating_sub(self.last_downloaded) as f64 / elapsed_secs;
+        let eta_secs = if speed > 0.0 && total > downloaded {
+            ((total - downloaded) as f64 / speed) as u64
+        } else {
+            0
+        };
+        let percent = if total > 0 {
+            100.0 * downloaded as f64 / total as f64
+        } else {
+            0.0
+        };
+        let _ = tx
+            .send(DownloadProgress {
+                downloaded,
+                total,
+                percent,
+                speed,
+                eta_secs,
+                file_index: self.file_index,
+                file_count: self.file_count,
+            })
+            .await;
+        self.last_emit = Instant::now();
+        self.last_downloaded = downloaded;
+    }
+}
+
+#[test]
+fn test_split_ranges() {
+    let mb = 1024 * 1024;
+    let segments = split_ranges(0, 10 * mb, 4);
+    assert_eq!(segments.len(), 4);
+    assert_eq!(segments.first().unwrap().start, 0);
+    assert_eq!(segments.last().unwrap().end, 10 * mb);
+
+    let segments = split_ranges(2 * mb, 10 * mb, 4);
+    assert!(segments[0].is_done());
+    assert_eq!(segments[1].start, 2 * mb);
+}
+
+#[test]
+fn test_is_cdn_error() {
+    let stalled = anyhow::Error::from(StreamEnded {
+        downloaded: 5,
+        total: 10,
+    });
+    assert!(is_cdn_error(&stalled));
+    assert!(!is_cdn_error(&anyhow::Error::from(Interrupted)));
+    let disk = anyhow::Error::from(std::io::Error::other("disk full")).context("write");
+    assert!(!is_cdn_error(&disk));
+}
@@ -1,14 +1,203 @@
-use crate::down_bangumi;
+use crate::down_au;
+use crate::down_bangumi::{self, BangumiEpisode, EpisodeSelection};
 use crate::down_bv;
-use crate::progress;
+use crate::down_cheese;
+use crate::down_collection;
+use crate::down_favlist;
+use crate::down_space::{self, SpaceFilter};
+use crate::download::TaskContext;
+use crate::live::{self, SplitRule};
 use anyhow::{Context, Result};
+use reqwest::redirect::Policy;
+use reqwest::Client;
 use tokio::sync::mpsc;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Video {
     ep_id: String,
     season_id: String,
     bv_id: String,
+    /// 多P视频要下载的分P，来自网址中的 `?p=`
+    pages: Pages,
+    /// 合集 id 及其 UP 主 mid，来自合集链接
+    collection_id: String,
+    mid: String,
+    /// BV 链接带 `?collection=1` 时下载视频所在的整个合集
+    expand_collection: bool,
+    /// 收藏夹 id（media_id）
+    fav_id: String,
+    /// UP 主空间链接的筛选条件，mid 复用上面的字段
+    space_filter: Option<SpaceFilter>,
+    /// 番剧介绍页 `md…`，解析时换成 season_id
+    media_id: String,
+    /// 音频区的单曲 `au…` 和歌单 `am…`
+    au_id: String,
+    am_id: String,
+    /// 课堂（cheese）的 ep/ss 链接，与番剧共用 ep_id 和 season_id
+    cheese: bool,
+    /// 番剧的剧集选择，来自 `?eps=`
+    episodes: Option<EpisodeSelection>,
+    /// 直播间号及录制文件的切分条件
+    live_room: String,
+    live_split: SplitRule,
+}
+
+/// 多P视频的分P选择
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Pages {
+    /// 未指定时只下载第一P
+    #[default]
+    First,
+    All,
+    /// 分P序号，从 1 开始
+    List(Vec<u32>),
+}
+
+impl Pages {
+    /// 解析 `p` 参数：`3`、`1,3-5` 或 `all`
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("all") {
+            return Ok(Pages::All);
+        }
+        let mut pages = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (start.trim().parse::<u32>()?, end.trim().parse::<u32>()?),
+                None => {
+                    let page = part.parse::<u32>()?;
+                    (page, page)
+                }
+            };
+            if start == 0 || start > end {
+                return Err(anyhow::anyhow!("Invalid page range: {}", part));
+            }
+            pages.extend(start..=end);
+        }
+        if pages.is_empty() {
+            return Err(anyhow::anyhow!("Empty page selection"));
+        }
+        pages.sort();
+        pages.dedup();
+        Ok(Pages::List(pages))
+    }
+
+    pub fn contains(&self, page: u32) -> bool {
+        match self {
+            Pages::First => page == 1,
+            Pages::All => true,
+            Pages::List(pages) => pages.contains(&page),
+        }
+    }
+}
+
+/// 短链接的域名，需要跟随跳转拿到真实地址
+const SHORT_LINK_HOSTS: [&str; 4] = ["b23.tv", "bili2233.cn", "bili22.cn", "bili33.cn"];
+
+/// 解析用户输入：可以是链接、分享文案、短链接或 BV/av/ep/ss/md 号
+pub async fn resolve(input: &str) -> Result<Video> {
+    let mut url = extract_url(input);
+    if SHORT_LINK_HOSTS.contains(&host(&url)) {
+        url = follow_short_link(&url).await?;
+        println!("short link resolved to {}", url);
+    }
+    let mut video = get_epid_season(&url)?;
+    if !video.media_id.is_empty() {
+        video.season_id = down_bangumi::media_season_id(&video.media_id)
+            .await
+            .context("Failed to resolve media id")?;
+    }
+    Ok(video)
+}
+
+/// 从分享文案（如 `【标题】 https://b23.tv/xxxx`）中取出链接，找不到链接时原样返回
+fn extract_url(input: &str) -> String {
+    let input = input.trim();
+    let url_char = |c: char| c.is_ascii_graphic() && !matches!(c, '"' | '\'' | '<' | '>');
+    if let Some(start) = input.find("https://").or_else(|| input.find("http://")) {
+        return input[start..]
+            .chars()
+            .take_while(|c| url_char(*c))
+            .collect();
+    }
+    // 没有协议头的链接，如 `b23.tv/xxxx`、`m.bilibili.com/video/BV…`
+    input
+        .split(|c: char| !url_char(c))
+        .find(|token| {
+            token.contains("bilibili.com/")
+                || SHORT_LINK_HOSTS
+                    .iter()
+                    .any(|h| token.contains(&format!("{}/", h)))
+        })
+        .map(|token| format!("https://{}", token))
+        .unwrap_or_else(|| input.to_string())
+}
+
+/// 链接的域名，不含协议和端口
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    authority.split(':').next().unwrap_or("")
+}
+
+/// 跟随短链接的跳转，直到离开短链接域名
+async fn follow_short_link(url: &str) -> Result<String> {
+    let client = Client::builder().redirect(Policy::none()).build()?;
+    let mut url = url.to_string();
+    for _ in 0..5 {
+        let resp = client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to open short link {}", url))?;
+        let Some(location) = resp.headers().get("location") else {
+            return Err(anyhow::anyhow!(
+                "Short link {} did not redirect ({})",
+                url,
+                resp.status()
+            ));
+        };
+        let location = location.to_str().context("Invalid redirect location")?;
+        url = resp.url().join(location)?.to_string();
+        if !SHORT_LINK_HOSTS.contains(&host(&url)) {
+            return Ok(url);
+        }
+    }
+    Err(anyhow::anyhow!("Too many redirects for short link"))
+}
+
+/// av 号转 BV 号
+fn av_to_bv(aid: u64) -> String {
+    const TABLE: &[u8] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+    const XOR_CODE: u64 = 23442827791579;
+    const MAX_AID: u64 = 1 << 51;
+    let mut bytes = *b"BV1000000000";
+    let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+    let mut i = bytes.len() - 1;
+    while tmp > 0 {
+        bytes[i] = TABLE[(tmp % 58) as usize];
+        tmp /= 58;
+        i -= 1;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// 拆分查询字符串并做百分号解码（`+` 视为空格），无法解码的保留原样
+fn decode_query(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        urlencoding::decode(&s).map(|d| d.into_owned()).unwrap_or(s)
+    };
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| match kv.split_once('=') {
+            Some((k, v)) => (decode(k), decode(v)),
+            None => (decode(kv), String::new()),
+        })
+        .collect()
 }
 
 /// 获取网址中的epid/seasonid/bv
@@ -25,34 +214,118 @@ pub fn get_epid_season(url: &str) -> Result<Video> {
         .rev()
         .find(|&&x| !x.is_empty())
         .context("Failed to extract the last part of the URL path")?;
+    let query = decode_query(parts.get(1).copied().unwrap_or(""));
+    let param = |key: &str| {
+        query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    if let Some(video) = parse_collection(&path_parts, id, param("sid"), param("type")) {
+        return Ok(video);
+    }
+    // 收藏夹：space.bilibili.com/{mid}/favlist?fid=… 或 /medialist/detail/ml…
+    if *id == "favlist" {
+        let fav_id = param("fid").context("Favorites URL does not contain fid")?;
+        return Ok(Video {
+            fav_id: fav_id.to_string(),
+            ..Default::default()
+        });
+    }
+    if let Some(fav_id) = id
+        .strip_prefix("ml")
+        .filter(|i| i.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Ok(Video {
+            fav_id: fav_id.to_string(),
+            ..Default::default()
+        });
+    }
+    // 直播间：live.bilibili.com/{room} 或 live.bilibili.com/h5/{room}
+    if path_parts.iter().any(|p| p.ends_with("live.bilibili.com")) {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(anyhow::anyhow!("Live URL does not contain a room id"));
+        }
+        return Ok(Video {
+            live_room: id.to_string(),
+            live_split: SplitRule::parse(param("split_mb"), param("split_min"))?,
+            ..Default::default()
+        });
+    }
+    if let Some(mid) = parse_space(&path_parts) {
+        let filter =
+            SpaceFilter::parse(param("from"), param("to"), param("keyword"), param("max"))?;
+        return Ok(Video {
+            mid,
+            space_filter: Some(filter),
+            ..Default::default()
+        });
+    }
+    let pages = match param("p") {
+        Some(p) => Pages::parse(p).context("Invalid p parameter")?,
+        None => Pages::First,
+    };
+    let expand_collection = matches!(param("collection"), Some("1" | "true"));
+    let numeric = |prefix: &str| {
+        id.get(..prefix.len())
+            .filter(|p| p.eq_ignore_ascii_case(prefix))
+            .map(|_| &id[prefix.len()..])
+            .filter(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()))
+    };
+    if let Some(aid) = numeric("av") {
+        return Ok(Video {
+            bv_id: av_to_bv(aid.parse().context("Invalid av number")?),
+            pages,
+            expand_collection,
+            ..Default::default()
+        });
+    }
+    if let Some(au_id) = numeric("au") {
+        return Ok(Video {
+            au_id: au_id.to_string(),
+            ..Default::default()
+        });
+    }
+    if let Some(am_id) = numeric("am") {
+        return Ok(Video {
+            am_id: am_id.to_string(),
+            ..Default::default()
+        });
+    }
+    if let Some(media_id) = numeric("md") {
+        return Ok(Video {
+            media_id: media_id.to_string(),
+            ..Default::default()
+        });
+    }
+    let cheese = path_parts.contains(&"cheese");
+    let episodes = param("eps")
+        .map(EpisodeSelection::parse)
+        .transpose()
+        .context("Invalid eps parameter")?;
     if id.starts_with("ep") {
         let ep_id = id.trim_start_matches("ep").to_string();
         Ok(Video {
             ep_id,
-            season_id: String::new(),
-            bv_id: String::new(),
+            cheese,
+            episodes,
+            ..Default::default()
         })
     } else if id.starts_with("ss") {
         let season_id = id.trim_start_matches("ss").to_string();
         Ok(Video {
-            ep_id: String::new(),
             season_id,
-            bv_id: String::new(),
+            cheese,
+            episodes,
+            ..Default::default()
         })
-    } else if id.starts_with("BV") {
-        let bv_id = id.to_string();
-        Ok(Video {
-            ep_id: String::new(),
-            season_id: String::new(),
-            bv_id,
-        })
-    } else if id.starts_with("bv") {
-        let bv_id = format!("BV{}", id.trim_start_matches("bv"));
-
+    } else if id.starts_with("BV") || id.starts_with("bv") {
+        let bv_id = format!("BV{}", &id[2..]);
         Ok(Video {
-            ep_id: String::new(),
-            season_id: String::new(),
             bv_id,
+            pages,
+            expand_collection,
+            ..Default::default()
         })
     } else {
         Err(anyhow::anyhow!(
@@ -61,29 +334,111 @@ pub fn get_epid_season(url: &str) -> Result<Video> {
     }
 }
 
+/// UP 主空间：`space.bilibili.com/{mid}`，可带 `/video` 或 `/upload/video`；
+/// 手机版为 `m.bilibili.com/space/{mid}`
+fn parse_space(path_parts: &[&str]) -> Option<String> {
+    let mid_index = match path_parts
+        .iter()
+        .position(|p| p.ends_with("space.bilibili.com"))
+    {
+        Some(host) => host + 1,
+        None => {
+            let host = path_parts.iter().position(|p| *p == "m.bilibili.com")?;
+            (path_parts.get(host + 1) == Some(&"space")).then_some(host + 2)?
+        }
+    };
+    let mid = path_parts.get(mid_index)?;
+    if mid.is_empty() || !mid.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let rest: Vec<&str> = path_parts[mid_index + 1..]
+        .iter()
+        .copied()
+        .filter(|p| !p.is_empty())
+        .collect();
+    matches!(rest.as_slice(), [] | ["video"] | ["upload", "video"]).then(|| mid.to_string())
+}
+
+/// 合集链接：`space.bilibili.com/{mid}/channel/collectiondetail?sid={id}`
+/// 或 `space.bilibili.com/{mid}/lists/{id}?type=season`
+fn parse_collection(
+    path_parts: &[&str],
+    last: &str,
+    sid: Option<&str>,
+    kind: Option<&str>,
+) -> Option<Video> {
+    let host = path_parts
+        .iter()
+        .position(|p| p.ends_with("space.bilibili.com"))?;
+    let mid = path_parts.get(host + 1)?.to_string();
+    let collection_id = match path_parts.get(host + 2).copied() {
+        Some("channel") if last == "collectiondetail" => sid?.to_string(),
+        // type=series 是视频列表，不是合集
+        Some("lists") if kind.is_none_or(|k| k == "season") => last.to_string(),
+        _ => return None,
+    };
+    Some(Video {
+        collection_id,
+        mid,
+        ..Default::default()
+    })
+}
+
 pub async fn choose_download_method(
     video: &Video,
     rsl: &str,
     save_path: &str,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<String> {
     let mut title = String::new();
-    if !video.ep_id.is_empty() || !video.season_id.is_empty() {
+    if !video.live_room.is_empty() {
+        title =
+            live::record_main(&video.live_room, video.live_split, save_path, ctx, title_tx).await?;
+    } else if !video.au_id.is_empty() {
+        title = down_au::down_main(&video.au_id, save_path, ctx, title_tx).await?;
+    } else if !video.am_id.is_empty() {
+        title = down_au::down_menu(&video.am_id, save_path, ctx, title_tx).await?;
+    } else if let Some(filter) = &video.space_filter {
+        title = down_space::down_main(&video.mid, filter, rsl, save_path, ctx, title_tx).await?;
+    } else if !video.fav_id.is_empty() {
+        title = down_favlist::down_main(&video.fav_id, rsl, save_path, ctx, title_tx).await?;
+    } else if !video.collection_id.is_empty() || video.expand_collection {
+        title = down_collection::down_main(
+            (&video.mid, &video.collection_id),
+            &video.bv_id,
+            rsl,
+            save_path,
+            ctx,
+            title_tx,
+        )
+        .await?;
+    } else if video.cheese {
+        title = down_cheese::down_main(
+            (&video.ep_id, &video.season_id),
+            rsl,
+            save_path,
+            ctx,
+            title_tx,
+        )
+        .await?;
+    } else if !video.ep_id.is_empty() || !video.season_id.is_empty() {
         down_bangumi::down_main(
             (&video.ep_id, &video.season_id),
+            video.episodes.as_ref(),
             rsl,
             save_path.to_string(),
-            progress_tx,
+            ctx,
             title_tx,
         )
         .await?;
     } else if !video.bv_id.is_empty() {
         title = down_bv::down_main(
             &video.bv_id,
+            &video.pages,
             rsl,
             save_path.to_string(),
-            progress_tx,
+            ctx,
             title_tx,
         )
         .await?;
@@ -93,10 +448,42 @@ pub async fn choose_download_method(
     Ok(title)
 }
 
+/// 多P视频的分P列表
+pub async fn get_pages(video: &Video) -> Result<Vec<down_bv::Page>> {
+    if video.bv_id.is_empty() {
+        return Err(anyhow::anyhow!("Only BV videos have pages"));
+    }
+    down_bv::bv_pages(&video.bv_id).await
+}
+
+/// 番剧的正片和番外列表
+pub async fn get_episodes(video: &Video) -> Result<Vec<BangumiEpisode>> {
+    if video.cheese || (video.ep_id.is_empty() && video.season_id.is_empty()) {
+        return Err(anyhow::anyhow!("Only bangumi have episodes"));
+    }
+    down_bangumi::season_episodes(&video.ep_id, &video.season_id).await
+}
+
 pub async fn get_title_pic(video: &Video) -> Result<(String, String)> {
     let mut title = String::new();
     let mut pic = String::new();
-    if !video.ep_id.is_empty() || !video.season_id.is_empty() {
+    if !video.live_room.is_empty() {
+        (title, pic) = live::room_title(&video.live_room).await?;
+    } else if !video.au_id.is_empty() {
+        (title, pic) = down_au::song_title(&video.au_id).await?;
+    } else if !video.am_id.is_empty() {
+        (title, pic) = down_au::menu_title(&video.am_id).await?;
+    } else if video.space_filter.is_some() {
+        (title, pic) = down_space::space_title(&video.mid).await?;
+    } else if !video.fav_id.is_empty() {
+        (title, pic) = down_favlist::favlist_title(&video.fav_id).await?;
+    } else if !video.collection_id.is_empty() || video.expand_collection {
+        (title, pic) =
+            down_collection::collection_title((&video.mid, &video.collection_id), &video.bv_id)
+                .await?;
+    } else if video.cheese {
+        (title, pic) = down_cheese::course_title(&video.ep_id, &video.season_id).await?;
+    } else if !video.ep_id.is_empty() || !video.season_id.is_empty() {
         (title, pic) = down_bangumi::bangumi_title(&video.ep_id, &video.season_id).await?;
     } else if !video.bv_id.is_empty() {
         (title, pic) = down_bv::bv_title(&video.bv_id).await?;
@@ -105,3 +492,125 @@ pub async fn get_title_pic(video: &Video) -> Result<(String, String)> {
     }
     Ok((title, pic))
 }
+
+#[test]
+fn test_pages() {
+    assert_eq!(Pages::parse("all").unwrap(), Pages::All);
+    assert_eq!(
+        Pages::parse("3-5,1").unwrap(),
+        Pages::List(vec![1, 3, 4, 5])
+    );
+    assert!(Pages::parse("0").is_err());
+    assert!(Pages::parse("5-3").is_err());
+    let video =
+        get_epid_season("https://www.bilibili.com/video/BV1xx411c7mD/?p=2&vd_source=abc").unwrap();
+    assert_eq!(video.pages, Pages::List(vec![2]));
+    // 复制来的网址中逗号常被编码
+    let video = get_epid_season("https://www.bilibili.com/video/BV1xx411c7mD/?p=1%2C3-5").unwrap();
+    assert_eq!(video.pages, Pages::List(vec![1, 3, 4, 5]));
+    assert!(Pages::First.contains(1) && !Pages::First.contains(2));
+}
+
+#[test]
+fn test_collection_url() {
+    let video =
+        get_epid_season("https://space.bilibili.com/23947287/channel/collectiondetail?sid=1234")
+            .unwrap();
+    assert_eq!(
+        (video.mid.as_str(), video.collection_id.as_str()),
+        ("23947287", "1234")
+    );
+    let video =
+        get_epid_season("https://space.bilibili.com/23947287/lists/5678?type=season").unwrap();
+    assert_eq!(video.collection_id, "5678");
+    assert!(get_epid_season("https://space.bilibili.com/23947287/lists/5678?type=series").is_err());
+    let video =
+        get_epid_season("https://www.bilibili.com/video/BV1xx411c7mD?collection=1").unwrap();
+    assert!(video.expand_collection && video.collection_id.is_empty());
+}
+
+#[test]
+fn test_favlist_url() {
+    let video =
+        get_epid_season("https://space.bilibili.com/123/favlist?fid=456&ftype=create").unwrap();
+    assert_eq!(video.fav_id, "456");
+    let video = get_epid_season("https://www.bilibili.com/medialist/detail/ml789").unwrap();
+    assert_eq!(video.fav_id, "789");
+    assert!(get_epid_season("https://space.bilibili.com/123/favlist").is_err());
+}
+
+#[test]
+fn test_space_url() {
+    let video = get_epid_season(
+        "https://space.bilibili.com/123/upload/video?from=2024-01-01&keyword=%E6%95%99%E7%A8%8B&max=5",
+    )
+    .unwrap();
+    assert_eq!(video.mid, "123");
+    let filter = video.space_filter.unwrap();
+    assert_eq!(filter.keyword, "教程");
+    assert_eq!(filter.max_count, Some(5));
+    assert!(get_epid_season("https://space.bilibili.com/123").is_ok());
+    assert!(get_epid_season("https://space.bilibili.com/123/dynamic").is_err());
+}
+
+#[test]
+fn test_av_to_bv() {
+    assert_eq!(av_to_bv(170001), "BV17x411w7KC");
+    assert_eq!(av_to_bv(2), "BV1xx411c7mD");
+    let video = get_epid_season("https://www.bilibili.com/video/av170001/?p=2").unwrap();
+    assert_eq!(video.bv_id, "BV17x411w7KC");
+    assert_eq!(video.pages, Pages::List(vec![2]));
+}
+
+#[test]
+fn test_extract_url() {
+    assert_eq!(
+        extract_url("【测试视频-哔哩哔哩】 https://b23.tv/AbCd123"),
+        "https://b23.tv/AbCd123"
+    );
+    assert_eq!(
+        extract_url("看看这个 b23.tv/AbCd123 吧"),
+        "https://b23.tv/AbCd123"
+    );
+    assert_eq!(extract_url(" BV1xx411c7mD "), "BV1xx411c7mD");
+    assert_eq!(host("https://b23.tv/AbCd123"), "b23.tv");
+    assert_eq!(host("http://127.0.0.1:8765/x"), "127.0.0.1");
+}
+
+#[tokio::test]
+async fn test_resolve_without_network() {
+    let video = resolve("【标题】 https://m.bilibili.com/video/BV1xx411c7mD?share_source=copy")
+        .await
+        .unwrap();
+    assert_eq!(video.bv_id, "BV1xx411c7mD");
+    let video = resolve("https://m.bilibili.com/bangumi/play/ep12345")
+        .await
+        .unwrap();
+    assert_eq!(video.ep_id, "12345");
+    let video = resolve("https://m.bilibili.com/space/123").await.unwrap();
+    assert_eq!(video.mid, "123");
+    let video = get_epid_season("https://www.bilibili.com/bangumi/media/md28229002/").unwrap();
+    assert_eq!(video.media_id, "28229002");
+    let video = get_epid_season("https://www.bilibili.com/cheese/play/ep1234").unwrap();
+    assert!(video.cheese && video.ep_id == "1234");
+    let video = get_epid_season("https://www.bilibili.com/bangumi/play/ss1234").unwrap();
+    assert!(!video.cheese && video.season_id == "1234");
+    let video = get_epid_season("https://www.bilibili.com/bangumi/play/ep1234?eps=extras").unwrap();
+    assert_eq!(video.episodes.unwrap().main, None);
+    let video =
+        get_epid_season("https://www.bilibili.com/bangumi/play/ep1234?eps=1-2%2Cextras").unwrap();
+    let episodes = video.episodes.unwrap();
+    assert!(episodes.extras && episodes.main == Some(Pages::List(vec![1, 2])));
+    let video = resolve("https://m.bilibili.com/audio/au1234")
+        .await
+        .unwrap();
+    assert_eq!(video.au_id, "1234");
+    let video = get_epid_season("https://www.bilibili.com/audio/am10624?type=3").unwrap();
+    assert_eq!(video.am_id, "10624");
+    let video =
+        get_epid_season("https://live.bilibili.com/22603245?split_mb=512&spm_id=1").unwrap();
+    assert_eq!(video.live_room, "22603245");
+    assert_eq!(video.live_split.max_bytes, Some(512 * 1024 * 1024));
+    let video = resolve("av2").await.unwrap();
+    assert_eq!(video.bv_id, "BV1xx411c7mD");
+}
@@ -4,21 +4,40 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod config;
+mod control;
+mod down_au;
 mod down_bangumi;
 mod down_bv;
+mod down_cheese;
+mod down_collection;
+mod down_favlist;
+mod down_interactive;
+mod down_space;
+mod download;
 mod init_;
+mod live;
+mod playurl;
 mod progress;
 mod qrcode_login;
+mod queue;
+mod ratelimit;
 mod refresh_cookie;
 mod resolution;
+mod resume;
+mod retry;
+mod verify;
+mod watchlater;
 mod wbi;
 
 use anyhow::Result;
 use config::ConfigState;
+use download::TaskContext;
+use queue::{DownloadTask, QueueState};
+use ratelimit::SpeedLimitState;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
-use tauri::Emitter;
+use tauri::Manager;
 use tokio::sync::mpsc;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +51,15 @@ struct DownloadResult {
     success: bool,
     message: String,
     title: Option<String>,
+    /// 实际使用的 CDN 主机
+    #[serde(default)]
+    hosts: Vec<String>,
+    /// 批量下载中被跳过的条目（失效视频、已下载等）
+    #[serde(default)]
+    skipped: Vec<String>,
+    /// 实际下载的视频编码
+    #[serde(default)]
+    codecs: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,16 +72,12 @@ struct LoginResult {
 /// 获取支持的分辨率列表
 #[tauri::command]
 fn get_resolutions() -> Vec<String> {
-    vec![
-        "HDR".to_string(),
-        "4K".to_string(),
-        "1080P+".to_string(),
-        "1080P60".to_string(),
-        "1080P".to_string(),
-        "720P".to_string(),
-        "480P".to_string(),
-        "360P".to_string(),
-    ]
+    resolution::Quality::ALL
+        .iter()
+        .rev()
+        .map(|q| q.to_string())
+        .chain([resolution::AUDIO_ONLY.to_string()])
+        .collect()
 }
 
 /// 登录 - 生成二维码并返回路径
@@ -87,7 +111,9 @@ async fn logout() -> Result<(), String> {
 /// 获取视频信息（标题和封面）
 #[tauri::command]
 async fn get_video_info(url: String) -> Result<VideoInfo, String> {
-    let video = init_::get_epid_season(&url).map_err(|e| format!("解析 URL 失败: {}", e))?;
+    let video = init_::resolve(&url)
+        .await
+        .map_err(|e| format!("解析 URL 失败: {}", e))?;
 
     let (title, pic_url) = init_::get_title_pic(&video)
         .await
@@ -96,15 +122,39 @@ async fn get_video_info(url: String) -> Result<VideoInfo, String> {
     Ok(VideoInfo { title, pic_url })
 }
 
+/// 获取多P视频的分P列表，下载时在网址后加 `?p=1,3-5` 或 `?p=all` 选择分P
+#[tauri::command]
+async fn get_video_pages(url: String) -> Result<Vec<down_bv::Page>, String> {
+    let video = init_::resolve(&url)
+        .await
+        .map_err(|e| format!("解析 URL 失败: {}", e))?;
+    init_::get_pages(&video)
+        .await
+        .map_err(|e| format!("获取分P列表失败: {}", e))
+}
+
+/// 获取番剧的正片和番外列表，下载时在网址后加 `?eps=1-12,15`、`?eps=extras` 或 `?eps=all` 选择剧集
+#[tauri::command]
+async fn get_bangumi_episodes(url: String) -> Result<Vec<down_bangumi::BangumiEpisode>, String> {
+    let video = init_::resolve(&url)
+        .await
+        .map_err(|e| format!("解析 URL 失败: {}", e))?;
+    init_::get_episodes(&video)
+        .await
+        .map_err(|e| format!("获取剧集列表失败: {}", e))
+}
+
 /// 内部：执行单任务下载并可选上报进度与标题
 async fn download_video_with_tx(
     url: String,
     resolution: String,
     save_path: String,
-    progress_tx: Option<mpsc::Sender<progress::DownloadProgress>>,
+    ctx: &TaskContext,
     title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
 ) -> Result<DownloadResult, String> {
-    let video = init_::get_epid_season(&url).map_err(|e| format!("解析 URL 失败: {}", e))?;
+    let video = init_::resolve(&url)
+        .await
+        .map_err(|e| format!("解析 URL 失败: {}", e))?;
 
     let rsl = if resolution.is_empty() {
         "4K".to_string()
@@ -112,46 +162,56 @@ async fn download_video_with_tx(
         resolution
     };
 
-    match init_::choose_download_method(&video, &rsl, &save_path, progress_tx, title_tx).await {
+    let result = init_::choose_download_method(&video, &rsl, &save_path, ctx, title_tx).await;
+    let skipped = ctx.skipped();
+    match result {
         Ok(title) => Ok(DownloadResult {
             success: true,
-            message: format!("下载完成: {}", title),
+            message: if skipped.is_empty() {
+                format!("下载完成: {}", title)
+            } else {
+                format!("下载完成: {}，跳过 {} 项", title, skipped.len())
+            },
             title: Some(title),
+            hosts: ctx.hosts(),
+            skipped,
+            codecs: ctx.codecs(),
         }),
         Err(e) => Ok(DownloadResult {
             success: false,
             message: format!("下载失败: {}", e),
             title: None,
+            hosts: ctx.hosts(),
+            skipped,
+            codecs: ctx.codecs(),
         }),
     }
 }
 
-/// 下载视频（带实时进度）
+/// 下载视频（带实时进度），通过下载队列执行，可暂停/取消
 #[tauri::command]
 async fn download_video(
     app: tauri::AppHandle,
+    queue: tauri::State<'_, QueueState>,
     url: String,
     resolution: String,
     save_path: String,
 ) -> Result<DownloadResult, String> {
-    let (tx, mut rx) = mpsc::channel::<progress::DownloadProgress>(64);
-    let app_emit = app.clone();
-    let recv_handle = tokio::spawn(async move {
-        while let Some(p) = rx.recv().await {
-            let _ = app_emit.emit("download-progress", &p);
-        }
-    });
-
-    let result = download_video_with_tx(url, resolution, save_path, Some(tx.clone()), None).await;
-    drop(tx);
-    recv_handle.await.ok();
-    result
+    let ids: Vec<u64> = queue
+        .enqueue(vec![url], &resolution, &save_path)?
+        .iter()
+        .map(|t| t.id)
+        .collect();
+    queue::pump(&app);
+    let mut results = queue.wait_all(&ids).await?;
+    results.pop().ok_or_else(|| "任务不存在".to_string())
 }
 
-/// 批量下载视频：每个任务独立进度，进度事件带 url_index
+/// 批量下载视频：任务加入下载队列并发执行，进度事件带 url_index
 #[tauri::command]
 async fn download_videos(
     app: tauri::AppHandle,
+    queue: tauri::State<'_, QueueState>,
     urls: Vec<String>,
     resolution: String,
     save_path: String,
@@ -162,71 +222,150 @@ async fn download_videos(
         resolution
     };
 
-    let (tx_agg, mut rx_agg) = mpsc::channel::<(usize, progress::DownloadProgress)>(64);
-    let (title_tx, mut title_rx) = mpsc::channel::<(usize, String)>(8);
-    let app_emit = app.clone();
-    let recv_handle = tokio::spawn(async move {
-        while let Some((url_index, p)) = rx_agg.recv().await {
-            let payload = serde_json::json!({
-                "url_index": url_index,
-                "downloaded": p.downloaded,
-                "total": p.total,
-                "percent": p.percent,
-                "speed": p.speed,
-                "eta_secs": p.eta_secs,
-                "file_index": p.file_index,
-                "file_count": p.file_count,
-            });
-            let _ = app_emit.emit("download-progress", payload);
-        }
-    });
-    let app_title = app.clone();
-    let title_handle = tokio::spawn(async move {
-        while let Some((url_index, title)) = title_rx.recv().await {
-            let payload = serde_json::json!({ "url_index": url_index, "title": title });
-            let _ = app_title.emit("download-task-title", payload);
-        }
-    });
+    let ids: Vec<u64> = queue
+        .enqueue(urls, &rsl, &save_path)?
+        .iter()
+        .map(|t| t.id)
+        .collect();
+    queue::pump(&app);
+    queue.wait_all(&ids).await
+}
+
+/// 获取稍后再看列表（需要登录）
+#[tauri::command]
+async fn get_watch_later() -> Result<Vec<watchlater::WatchLaterItem>, String> {
+    watchlater::list()
+        .await
+        .map_err(|e| format!("获取稍后再看失败: {}", e))
+}
 
+/// 下载稍后再看中选中的视频，remove_after 为 true 时把下载成功的视频移出稍后再看
+#[tauri::command]
+async fn download_watch_later(
+    app: tauri::AppHandle,
+    queue: tauri::State<'_, QueueState>,
+    bv_ids: Vec<String>,
+    resolution: String,
+    save_path: String,
+    remove_after: bool,
+) -> Result<Vec<DownloadResult>, String> {
+    let (items, invalid): (Vec<_>, Vec<_>) = watchlater::list()
+        .await
+        .map_err(|e| format!("获取稍后再看失败: {}", e))?
+        .into_iter()
+        .filter(|item| bv_ids.contains(&item.bv_id))
+        .partition(|item| !item.invalid);
+    if items.is_empty() && invalid.is_empty() {
+        return Err("稍后再看中没有选中的视频".to_string());
+    }
     let mut results = Vec::new();
-    for (index, url) in urls.into_iter().enumerate() {
-        let (tx_per, mut rx_per) = mpsc::channel::<progress::DownloadProgress>(64);
-        let tx_agg = tx_agg.clone();
-        let index = index;
-        let forwarder = tokio::spawn(async move {
-            while let Some(p) = rx_per.recv().await {
-                let _ = tx_agg.send((index, p)).await;
-            }
-        });
-
-        let result = download_video_with_tx(
-            url,
-            rsl.clone(),
-            save_path.clone(),
-            Some(tx_per.clone()),
-            Some((index, title_tx.clone())),
-        )
-        .await;
-        drop(tx_per);
-        forwarder.await.ok();
-
-        match result {
-            Ok(r) => results.push(r),
-            Err(e) => results.push(DownloadResult {
-                success: false,
-                message: e,
-                title: None,
-            }),
-        }
+    if !items.is_empty() {
+        let urls = items.iter().map(|item| item.url()).collect();
+        let ids: Vec<u64> = queue
+            .enqueue(urls, &resolution, &save_path)?
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        queue::pump(&app);
+        results = queue.wait_all(&ids).await?;
     }
 
-    drop(tx_agg);
-    drop(title_tx);
-    recv_handle.await.ok();
-    title_handle.await.ok();
+    if remove_after {
+        let done: Vec<i64> = items
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.success)
+            .map(|(item, _)| item.aid)
+            .collect();
+        watchlater::remove(&done)
+            .await
+            .map_err(|e| format!("移出稍后再看失败: {}", e))?;
+    }
+    // 失效视频不进入队列，直接记为跳过
+    results.extend(invalid.iter().filter_map(|item| {
+        let reason = item.skip_reason()?;
+        Some(DownloadResult {
+            success: false,
+            message: format!("已跳过: {}", reason),
+            title: Some(item.title.clone()),
+            hosts: Vec::new(),
+            skipped: vec![reason],
+            codecs: Vec::new(),
+        })
+    }));
     Ok(results)
 }
 
+/// 添加下载任务到队列
+#[tauri::command]
+async fn queue_add(
+    app: tauri::AppHandle,
+    queue: tauri::State<'_, QueueState>,
+    urls: Vec<String>,
+    resolution: String,
+    save_path: String,
+) -> Result<Vec<DownloadTask>, String> {
+    let tasks = queue.enqueue(urls, &resolution, &save_path)?;
+    queue::pump(&app);
+    Ok(tasks)
+}
+
+/// 获取下载队列
+#[tauri::command]
+async fn queue_list(queue: tauri::State<'_, QueueState>) -> Result<Vec<DownloadTask>, String> {
+    queue.list()
+}
+
+/// 调整任务在队列中的位置
+#[tauri::command]
+async fn queue_move(
+    queue: tauri::State<'_, QueueState>,
+    id: u64,
+    index: usize,
+) -> Result<(), String> {
+    queue.move_task(id, index)
+}
+
+/// 从队列中删除任务
+#[tauri::command]
+async fn queue_remove(queue: tauri::State<'_, QueueState>, id: u64) -> Result<(), String> {
+    queue.remove(id)
+}
+
+/// 清除队列中已结束的任务，返回清除的数量
+#[tauri::command]
+async fn queue_clear_finished(queue: tauri::State<'_, QueueState>) -> Result<usize, String> {
+    queue.clear_finished()
+}
+
+/// 暂停下载任务
+#[tauri::command]
+async fn pause_task(queue: tauri::State<'_, QueueState>, id: u64) -> Result<(), String> {
+    queue.pause(id)
+}
+
+/// 继续下载任务
+#[tauri::command]
+async fn resume_task(
+    app: tauri::AppHandle,
+    queue: tauri::State<'_, QueueState>,
+    id: u64,
+) -> Result<(), String> {
+    queue.resume(id)?;
+    queue::pump(&app);
+    Ok(())
+}
+
+/// 取消下载任务，keep_files 为 true 时保留临时文件以便之后续传
+#[tauri::command]
+async fn cancel_task(
+    queue: tauri::State<'_, QueueState>,
+    id: u64,
+    keep_files: bool,
+) -> Result<(), String> {
+    queue.cancel(id, keep_files)
+}
+
 /// 获取保存路径
 #[tauri::command]
 async fn get_save_path(state: tauri::State<'_, ConfigState>) -> Result<String, String> {
@@ -245,6 +384,167 @@ async fn set_save_path(state: tauri::State<'_, ConfigState>, path: String) -> Re
     Ok(())
 }
 
+/// 获取单个流的下载连接数
+#[tauri::command]
+async fn get_connections(state: tauri::State<'_, ConfigState>) -> Result<usize, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.connections)
+}
+
+/// 设置单个流的下载连接数
+#[tauri::command]
+async fn set_connections(
+    state: tauri::State<'_, ConfigState>,
+    connections: usize,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.connections = connections.clamp(1, 16);
+    }
+    state.save()?;
+    Ok(())
+}
+
+/// 获取同时下载的任务数
+#[tauri::command]
+async fn get_max_concurrent(state: tauri::State<'_, ConfigState>) -> Result<usize, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.max_concurrent)
+}
+
+/// 设置同时下载的任务数
+#[tauri::command]
+async fn set_max_concurrent(
+    app: tauri::AppHandle,
+    state: tauri::State<'_, ConfigState>,
+    max_concurrent: usize,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.max_concurrent = max_concurrent.clamp(1, 8);
+    }
+    state.save()?;
+    queue::pump(&app);
+    Ok(())
+}
+
+/// 获取临时错误的最多重试次数
+#[tauri::command]
+async fn get_max_retries(state: tauri::State<'_, ConfigState>) -> Result<u32, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.max_retries)
+}
+
+/// 设置临时错误的最多重试次数，对之后开始的任务生效
+#[tauri::command]
+async fn set_max_retries(
+    state: tauri::State<'_, ConfigState>,
+    max_retries: u32,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.max_retries = max_retries.min(10);
+    }
+    state.save()?;
+    Ok(())
+}
+
+/// 获取视频编码偏好（按优先级排列，空为不限）
+#[tauri::command]
+async fn get_codec_preference(
+    state: tauri::State<'_, ConfigState>,
+) -> Result<Vec<resolution::Codec>, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.codec_preference.clone())
+}
+
+/// 设置视频编码偏好，如 `["avc", "hevc"]`，对之后开始的任务生效
+#[tauri::command]
+async fn set_codec_preference(
+    state: tauri::State<'_, ConfigState>,
+    codecs: Vec<resolution::Codec>,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        let mut order = Vec::new();
+        for codec in codecs {
+            if !order.contains(&codec) {
+                order.push(codec);
+            }
+        }
+        config.codec_preference = order;
+    }
+    state.save()?;
+    Ok(())
+}
+
+/// 获取是否严格按编码偏好下载
+#[tauri::command]
+async fn get_codec_strict(state: tauri::State<'_, ConfigState>) -> Result<bool, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.codec_strict)
+}
+
+/// 设置严格模式：开启后没有偏好中的编码时下载失败
+#[tauri::command]
+async fn set_codec_strict(
+    state: tauri::State<'_, ConfigState>,
+    strict: bool,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.codec_strict = strict;
+    }
+    state.save()?;
+    Ok(())
+}
+
+/// 获取全局限速（KiB/s，0 为不限速）
+#[tauri::command]
+async fn get_speed_limit(state: tauri::State<'_, ConfigState>) -> Result<u64, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.speed_limit)
+}
+
+/// 设置全局限速，立即作用于正在下载的任务
+#[tauri::command]
+async fn set_speed_limit(
+    state: tauri::State<'_, ConfigState>,
+    limits: tauri::State<'_, SpeedLimitState>,
+    speed_limit: u64,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.speed_limit = speed_limit;
+    }
+    state.save()?;
+    limits.set_global(speed_limit);
+    Ok(())
+}
+
+/// 获取单任务限速（KiB/s，0 为不限速）
+#[tauri::command]
+async fn get_task_speed_limit(state: tauri::State<'_, ConfigState>) -> Result<u64, String> {
+    let config = state.config.lock().map_err(|e| e.to_string())?;
+    Ok(config.task_speed_limit)
+}
+
+/// 设置单任务限速，立即作用于正在下载的任务
+#[tauri::command]
+async fn set_task_speed_limit(
+    state: tauri::State<'_, ConfigState>,
+    limits: tauri::State<'_, SpeedLimitState>,
+    task_speed_limit: u64,
+) -> Result<(), String> {
+    {
+        let mut config = state.config.lock().map_err(|e| e.to_string())?;
+        config.task_speed_limit = task_speed_limit;
+    }
+    state.save()?;
+    limits.set_per_task(task_speed_limit);
+    Ok(())
+}
+
 /// 检查是否已登录
 #[tauri::command]
 async fn check_login() -> Result<bool, String> {
@@ -266,20 +566,75 @@ async fn read_history_log() -> Result<String, String> {
 pub fn run() {
     // 初始化配置，如果不存在先创建一个默认的
     let config_state = ConfigState::new();
+    let queue_state = QueueState::new();
+    let speed_limit_state = config_state
+        .config
+        .lock()
+        .map(|c| SpeedLimitState::new(c.speed_limit, c.task_speed_limit))
+        .unwrap_or_else(|_| SpeedLimitState::new(0, 0));
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(config_state) // 注入状态
+        .manage(queue_state)
+        .manage(speed_limit_state)
+        .setup(|app| {
+            // 清理上次中途退出时遗留的合并临时文件，之后才开始下载
+            let mut dirs: Vec<String> = app
+                .state::<QueueState>()
+                .list()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|t| t.save_path)
+                .collect();
+            if let Ok(config) = app.state::<ConfigState>().config.lock() {
+                dirs.push(config.save_path.clone());
+            }
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                resume::clean_temp_outputs(Path::new(&dir));
+            }
+            // 恢复上次未完成的队列任务
+            queue::pump(app.handle());
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_resolutions,
             get_save_path,
             set_save_path,
+            get_connections,
+            set_connections,
+            get_max_concurrent,
+            set_max_concurrent,
+            get_max_retries,
+            set_max_retries,
+            get_speed_limit,
+            set_speed_limit,
+            get_task_speed_limit,
+            set_task_speed_limit,
+            get_codec_preference,
+            set_codec_preference,
+            get_codec_strict,
+            set_codec_strict,
             check_login,
             login,
             logout,
             get_video_info,
+            get_video_pages,
+            get_bangumi_episodes,
             download_video,
             download_videos,
+            get_watch_later,
+            download_watch_later,
+            queue_add,
+            queue_list,
+            queue_move,
+            queue_remove,
+            queue_clear_finished,
+            pause_task,
+            resume_task,
+            cancel_task,
             read_history_log
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,737 @@
+use crate::down_bangumi::{append_history, read_cookie_or_not, remove_punctuation};
+use crate::download::{next_chunk, Progress, TaskContext};
+use crate::refresh_cookie::create_headers;
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Url};
+use serde_json::Value;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// 等待响应头的超时
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+/// 断流后重连前的等待
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// 连续这么多次连接失败或没有数据（且仍在直播）时放弃
+const MAX_RECONNECTS: u32 = 10;
+/// 写入缓冲区大小
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// 录制文件的切分条件，都为 None 时不切分
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SplitRule {
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+impl SplitRule {
+    /// 解析 `split_mb`（MiB）和 `split_min`（分钟）参数，0 为不切分
+    pub fn parse(size_mb: Option<&str>, minutes: Option<&str>) -> Result<Self> {
+        let positive = |v: Option<&str>, what: &str| -> Result<Option<u64>> {
+            let v = v
+                .map(|v| v.parse::<u64>())
+                .transpose()
+                .with_context(|| format!("Invalid {}", what))?;
+            Ok(v.filter(|v| *v > 0))
+        };
+        Ok(Self {
+            max_bytes: positive(size_mb, "split_mb")?.map(|mb| mb * 1024 * 1024),
+            max_duration: positive(minutes, "split_min")?.map(|m| Duration::from_secs(m * 60)),
+        })
+    }
+
+    fn reached(&self, bytes: u64, elapsed: Duration) -> bool {
+        self.max_bytes.is_some_and(|max| bytes >= max)
+            || self.max_duration.is_some_and(|max| elapsed >= max)
+    }
+}
+
+/// 直播流协议
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LiveFormat {
+    Flv,
+    /// HLS，ts 分片
+    HlsTs,
+    /// HLS，fmp4 分片
+    HlsFmp4,
+}
+
+impl LiveFormat {
+    fn ext(self) -> &'static str {
+        match self {
+            LiveFormat::Flv => "flv",
+            LiveFormat::HlsTs => "ts",
+            LiveFormat::HlsFmp4 => "mp4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct LiveUrl {
+    url: String,
+    format: LiveFormat,
+}
+
+/// 直播流的来源，测试时用本地 HTTP 服务代替直播间
+trait LiveSource {
+    /// 获取当前的拉流地址，地址会过期，每次重连都重新获取
+    async fn stream_url(&self) -> Result<LiveUrl>;
+    /// 断流后检查是否仍在直播
+    async fn is_live(&self) -> Result<bool>;
+}
+
+/// 单次连接的结束方式
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// 达到切分条件，需要换新文件
+    Split,
+    /// 服务器结束了流
+    Ended,
+    /// 任务被暂停或取消
+    Interrupted,
+}
+
+/// 直播间
+struct Room {
+    client: Client,
+    headers: HeaderMap,
+    room_id: String,
+}
+
+impl LiveSource for Room {
+    async fn stream_url(&self) -> Result<LiveUrl> {
+        let json: Value = self
+            .client
+            .get("https://api.live.bilibili.com/xlive/web-room/v2/index/getRoomPlayInfo")
+            .headers(self.headers.clone())
+            .query(&[
+                ("room_id", self.room_id.as_str()),
+                ("protocol", "0,1"),
+                ("format", "0,1,2"),
+                ("codec", "0"),
+                ("qn", "10000"),
+                ("platform", "web"),
+                ("ptype", "8"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Failed to parse live play info")?;
+        ApiError::check(&json)?;
+        parse_play_info(&json["data"]).context("No live stream url, the room may be offline")
+    }
+
+    async fn is_live(&self) -> Result<bool> {
+        let info = get_room_info(&self.client, &self.room_id, self.headers.clone()).await?;
+        Ok(info.live)
+    }
+}
+
+/// 从 getRoomPlayInfo 中选出拉流地址：优先 FLV，其次 HLS（fmp4、ts），编码优先 avc
+fn parse_play_info(data: &Value) -> Option<LiveUrl> {
+    let streams = data["playurl_info"]["playurl"]["stream"].as_array()?;
+    let candidates = [
+        ("http_stream", "flv", LiveFormat::Flv),
+        ("http_hls", "fmp4", LiveFormat::HlsFmp4),
+        ("http_hls", "ts", LiveFormat::HlsTs),
+    ];
+    for (protocol, format_name, format) in candidates {
+        let codecs = streams
+            .iter()
+            .filter(|s| s["protocol_name"] == protocol)
+            .flat_map(|s| s["format"].as_array().into_iter().flatten())
+            .filter(|f| f["format_name"] == format_name)
+            .flat_map(|f| f["codec"].as_array().into_iter().flatten());
+        let mut codecs: Vec<&Value> = codecs.collect();
+        codecs.sort_by_key(|c| c["codec_name"] != "avc");
+        for codec in codecs {
+            let base_url = codec["base_url"].as_str().unwrap_or("");
+            let Some(info) = codec["url_info"].as_array().and_then(|i| i.first()) else {
+                continue;
+            };
+            let host = info["host"].as_str().unwrap_or("");
+            if host.is_empty() || base_url.is_empty() {
+                continue;
+            }
+            let extra = info["extra"].as_str().unwrap_or("");
+            return Some(LiveUrl {
+                url: format!("{}{}{}", host, base_url, extra),
+                format,
+            });
+        }
+    }
+    None
+}
+
+/// 直播间信息
+#[derive(Debug)]
+struct RoomInfo {
+    /// 长号
+    room_id: String,
+    uid: i64,
+    title: String,
+    cover: String,
+    live: bool,
+}
+
+/// 直播间信息，短号也可以查询
+async fn get_room_info(client: &Client, room_id: &str, headers: HeaderMap) -> Result<RoomInfo> {
+    let json: Value = client
+        .get("https://api.live.bilibili.com/room/v1/Room/get_info")
+        .headers(headers)
+        .query(&[("room_id", room_id)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Failed to parse room info")?;
+    ApiError::check(&json)?;
+    let data = &json["data"];
+    Ok(RoomInfo {
+        room_id: data["room_id"]
+            .as_i64()
+            .map_or_else(|| room_id.to_string(), |id| id.to_string()),
+        uid: data["uid"].as_i64().unwrap_or(0),
+        title: remove_punctuation(data["title"].as_str().unwrap_or(room_id)),
+        cover: data["user_cover"].as_str().unwrap_or("").to_string(),
+        live: data["live_status"].as_i64() == Some(1),
+    })
+}
+
+/// 主播名称，查询失败时用直播间号代替
+async fn get_uname(client: &Client, uid: i64, headers: HeaderMap) -> Result<String> {
+    let uid = uid.to_string();
+    let json: Value = client
+        .get("https://api.live.bilibili.com/live_user/v1/Master/info")
+        .headers(headers)
+        .query(&[("uid", uid.as_str())])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    ApiError::check(&json)?;
+    json["data"]["info"]["uname"]
+        .as_str()
+        .filter(|n| !n.is_empty())
+        .map(remove_punctuation)
+        .context("Missing uname")
+}
+
+/// 正在写入的录制文件
+struct OutputFile {
+    path: String,
+    file: BufWriter<File>,
+    written: u64,
+    started: Instant,
+    progress: Progress,
+}
+
+impl OutputFile {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await?;
+        self.written += data.len() as u64;
+        self.progress.update(self.written).await;
+        Ok(())
+    }
+
+    fn should_split(&self, rule: &SplitRule) -> bool {
+        rule.reached(self.written, self.started.elapsed())
+    }
+
+    /// 写完并关闭，没有数据的文件直接删除
+    async fn close(mut self) -> Result<Option<String>> {
+        self.file.flush().await?;
+        self.progress.finish(self.written).await;
+        if self.written == 0 {
+            let _ = tokio::fs::remove_file(&self.path).await;
+            return Ok(None);
+        }
+        println!("recorded {} ({} bytes)", self.path, self.written);
+        Ok(Some(self.path))
+    }
+}
+
+/// 录制过程：按连接写入文件，断流重连，达到条件时切分
+struct Recorder<'a> {
+    client: Client,
+    headers: HeaderMap,
+    /// 输出文件名前缀（含目录）
+    prefix: String,
+    split: SplitRule,
+    ctx: &'a TaskContext,
+    files: Vec<String>,
+    /// HLS 已写入的最后一个分片序号，切分后从下一个分片继续
+    hls_seq: Option<u64>,
+    /// 断流后重连前的等待
+    reconnect_delay: Duration,
+}
+
+impl Recorder<'_> {
+    /// 一直录制到直播结束或用户停止，返回录好的文件
+    async fn run<S: LiveSource>(mut self, source: &S) -> Result<Vec<String>> {
+        let mut failures = 0;
+        loop {
+            // 暂停时不写入，继续后重新连接并写到新文件；取消时结束录制，已录制的文件保留
+            if let Err(e) = self.ctx.control.checkpoint().await {
+                println!("recording cancelled, kept {} files", self.files.len());
+                return Err(e);
+            }
+            let (written, result) = match source.stream_url().await {
+                Ok(url) => self.record(&url).await,
+                Err(e) => (0, Err(e)),
+            };
+            // 录到数据说明这次重连成功；只有连续连不上或连上后没有数据的才计入失败
+            if written > 0 {
+                failures = 0;
+            }
+            match result {
+                Ok(Outcome::Split | Outcome::Interrupted) => continue,
+                Ok(Outcome::Ended) => {}
+                Err(e) => {
+                    println!("live stream interrupted: {:#}", e);
+                    self.hls_seq = None;
+                }
+            }
+            if written == 0 {
+                failures += 1;
+            }
+            // 断流：下播则结束，否则等一会儿重连
+            match source.is_live().await {
+                Ok(false) => break,
+                Ok(true) => {}
+                Err(e) => {
+                    println!("failed to check live status: {:#}", e);
+                    failures += 1;
+                }
+            }
+            if failures >= MAX_RECONNECTS {
+                if self.files.is_empty() {
+                    anyhow::bail!("连续 {} 次无法连接直播流", failures);
+                }
+                println!("giving up after {} reconnects", failures);
+                break;
+            }
+            println!("reconnecting live stream");
+            tokio::select! {
+                _ = tokio::time::sleep(self.reconnect_delay) => {}
+                _ = self.ctx.control.interrupted() => {}
+            }
+        }
+        Ok(self.files)
+    }
+
+    /// 录制一次连接，返回写入的字节数和结束方式
+    async fn record(&mut self, url: &LiveUrl) -> (u64, Result<Outcome>) {
+        let mut output = match self.create_output(url.format.ext()).await {
+            Ok(output) => output,
+            Err(e) => return (0, Err(e)),
+        };
+        let result = match url.format {
+            LiveFormat::Flv => self.record_flv(&url.url, &mut output).await,
+            LiveFormat::HlsTs | LiveFormat::HlsFmp4 => self.record_hls(&url.url, &mut output).await,
+        };
+        let written = output.written;
+        match output.close().await {
+            Ok(Some(path)) => self.files.push(path),
+            Ok(None) => {}
+            Err(e) => return (written, Err(e)),
+        }
+        (written, result)
+    }
+
+    /// 新建录制文件，以开始时间命名
+    async fn create_output(&self, ext: &str) -> Result<OutputFile> {
+        let time = (Utc::now() + chrono::Duration::hours(8)).format("%Y%m%d-%H%M%S");
+        let mut path = format!("{} {}.{}", self.prefix, time, ext);
+        let mut n = 1;
+        while Path::new(&path).exists() {
+            n += 1;
+            path = format!("{} {}-{}.{}", self.prefix, time, n, ext);
+        }
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create {}", path))?;
+        let index = self.files.len() as u32;
+        let mut progress = Progress::new(self.ctx.progress_tx.clone(), index, index + 1);
+        progress.start(0, 0);
+        println!("recording to {}", path);
+        Ok(OutputFile {
+            path,
+            file: BufWriter::with_capacity(WRITE_BUFFER_SIZE, file),
+            written: 0,
+            started: Instant::now(),
+            progress,
+        })
+    }
+
+    async fn get(&self, url: &str) -> Result<reqwest::Response> {
+        let resp = timeout(
+            RESPONSE_TIMEOUT,
+            self.client.get(url).headers(self.headers.clone()).send(),
+        )
+        .await
+        .context("Live stream response timed out")??;
+        Ok(resp.error_for_status()?)
+    }
+
+    /// FLV 是一条不断的 HTTP 响应；切分时重新连接，让新文件从 FLV 头开始
+    async fn record_flv(&self, url: &str, output: &mut OutputFile) -> Result<Outcome> {
+        let mut stream = self.get(url).await?.bytes_stream();
+        loop {
+            let chunk = tokio::select! {
+                chunk = next_chunk(&mut stream) => chunk?,
+                _ = self.ctx.control.interrupted() => return Ok(Outcome::Interrupted),
+            };
+            let Some(chunk) = chunk else {
+                return Ok(Outcome::Ended);
+            };
+            output.write(&chunk).await?;
+            if output.should_split(&self.split) {
+                return Ok(Outcome::Split);
+            }
+        }
+    }
+
+    /// HLS 反复拉取播放列表，按序号追加新分片；fmp4 每个文件先写初始化分片
+    async fn record_hls(&mut self, url: &str, output: &mut OutputFile) -> Result<Outcome> {
+        let base = Url::parse(url).context("Invalid playlist url")?;
+        let mut wrote_map = false;
+        loop {
+            let text = self.get(url).await?.text().await?;
+            let playlist = Playlist::parse(&text);
+            if let (false, Some(map)) = (wrote_map, &playlist.map) {
+                let bytes = self.get(base.join(map)?.as_str()).await?.bytes().await?;
+                output.write(&bytes).await?;
+                wrote_map = true;
+            }
+            for (seq, uri) in playlist.segments() {
+                if self.hls_seq.is_some_and(|last| seq <= last) {
+                    continue;
+                }
+                let segment_url = base.join(uri)?;
+                let bytes = tokio::select! {
+                    resp = self.get(segment_url.as_str()) => resp?.bytes().await?,
+                    _ = self.ctx.control.interrupted() => return Ok(Outcome::Interrupted),
+                };
+                output.write(&bytes).await?;
+                self.hls_seq = Some(seq);
+                if output.should_split(&self.split) {
+                    return Ok(Outcome::Split);
+                }
+            }
+            if playlist.ended {
+                return Ok(Outcome::Ended);
+            }
+            let wait = Duration::from_secs(playlist.target_duration.max(2) / 2);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.ctx.control.interrupted() => return Ok(Outcome::Interrupted),
+            }
+        }
+    }
+}
+
+/// HLS 媒体播放列表中录制需要的部分
+#[derive(Debug, Default, PartialEq)]
+struct Playlist {
+    media_sequence: u64,
+    target_duration: u64,
+    /// fmp4 的初始化分片
+    map: Option<String>,
+    uris: Vec<String>,
+    ended: bool,
+}
+
+impl Playlist {
+    fn parse(text: &str) -> Self {
+        let mut playlist = Playlist::default();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(v) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                playlist.media_sequence = v.trim().parse().unwrap_or(0);
+            } else if let Some(v) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+                playlist.target_duration = v.trim().parse().unwrap_or(0);
+            } else if let Some(v) = line.strip_prefix("#EXT-X-MAP:") {
+                playlist.map = v
+                    .split(',')
+                    .find_map(|attr| attr.trim().strip_prefix("URI="))
+                    .map(|uri| uri.trim_matches('"').to_string());
+            } else if line == "#EXT-X-ENDLIST" {
+                playlist.ended = true;
+            } else if !line.starts_with('#') {
+                playlist.uris.push(line.to_string());
+            }
+        }
+        playlist
+    }
+
+    /// 分片及其序号
+    fn segments(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.uris
+            .iter()
+            .enumerate()
+            .map(|(i, uri)| (self.media_sequence + i as u64, uri.as_str()))
+    }
+}
+
+/// 录制直播间，保存到以主播命名的文件夹；停止录制时保留已录制的内容
+pub async fn record_main(
+    room_id: &str,
+    split: SplitRule,
+    save_path: &str,
+    ctx: &TaskContext,
+    title_tx: Option<(usize, mpsc::Sender<(usize, String)>)>,
+) -> Result<String> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let headers = create_headers(&cookies);
+    let info = ctx
+        .retry("获取直播间信息", || {
+            get_room_info(&client, room_id, headers.clone())
+        })
+        .await?;
+    if let Some((idx, tx)) = &title_tx {
+        let _ = tx.send((*idx, info.title.clone())).await;
+    }
+    if !info.live {
+        anyhow::bail!("直播间 {} 未开播", room_id);
+    }
+    let uname = get_uname(&client, info.uid, headers.clone())
+        .await
+        .unwrap_or_else(|_| info.room_id.clone());
+    let folder = format!("{}/{}", save_path, uname);
+    std::fs::create_dir_all(&folder)?;
+    append_history(&format!("live{}", info.room_id), &info.title).await?;
+
+    let room = Room {
+        client: client.clone(),
+        headers: headers.clone(),
+        room_id: info.room_id.clone(),
+    };
+    let recorder = Recorder {
+        client,
+        headers,
+        prefix: format!("{}/{}", folder, info.title),
+        split,
+        ctx,
+        files: Vec::new(),
+        hls_seq: None,
+        reconnect_delay: RECONNECT_DELAY,
+    };
+    let files = recorder.run(&room).await?;
+    println!("recorded {} files of room {}", files.len(), info.room_id);
+    Ok(info.title)
+}
+
+pub async fn room_title(room_id: &str) -> Result<(String, String)> {
+    let client = reqwest::Client::new();
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    let info = get_room_info(&client, room_id, create_headers(&cookies)).await?;
+    Ok((info.title, info.cover))
+}
+
+#[test]
+fn test_parse_play_info() {
+    let codec = |name: &str, host: &str| {
+        serde_json::json!({
+            "codec_name": name, "base_url": "/live-bvc/1/live_1.flv?",
+            "url_info": [{ "host": host, "extra": "expires=1" }],
+        })
+    };
+    let data = serde_json::json!({ "playurl_info": { "playurl": { "stream": [
+        { "protocol_name": "http_hls", "format": [
+            { "format_name": "ts", "codec": [codec("avc", "https://hls.example")] },
+        ]},
+        { "protocol_name": "http_stream", "format": [
+            { "format_name": "flv", "codec": [codec("hevc", "https://b.example"), codec("avc", "https://a.example")] },
+        ]},
+    ]}}});
+    let url = parse_play_info(&data).unwrap();
+    assert_eq!(url.format, LiveFormat::Flv);
+    assert_eq!(url.url, "https://a.example/live-bvc/1/live_1.flv?expires=1");
+
+    let playlist = Playlist::parse(
+        "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:100\n#EXT-X-MAP:URI=\"h1.m4s\"\n#EXTINF:1.0,\n101.m4s\n#EXTINF:1.0,\n102.m4s\n#EXT-X-ENDLIST\n",
+    );
+    assert_eq!(playlist.map.as_deref(), Some("h1.m4s"));
+    assert_eq!(
+        playlist.segments().collect::<Vec<_>>(),
+        vec![(100, "101.m4s"), (101, "102.m4s")]
+    );
+    assert!(playlist.ended);
+    assert_eq!(
+        SplitRule::parse(Some("100"), Some("0")).unwrap(),
+        SplitRule {
+            max_bytes: Some(100 * 1024 * 1024),
+            max_duration: None
+        }
+    );
+}
+
+/// 本地 HTTP 服务代替直播间：每个连接发送 body_size 字节后断开，连接数用完后返回 404
+#[cfg(test)]
+struct LocalSource {
+    url: String,
+    /// 断流后还报告“直播中”的次数
+    live_checks: std::sync::atomic::AtomicU32,
+}
+
+#[cfg(test)]
+impl LiveSource for LocalSource {
+    async fn stream_url(&self) -> Result<LiveUrl> {
+        Ok(LiveUrl {
+            url: self.url.clone(),
+            format: LiveFormat::Flv,
+        })
+    }
+
+    async fn is_live(&self) -> Result<bool> {
+        use std::sync::atomic::Ordering;
+        let left = self.live_checks.load(Ordering::SeqCst);
+        if left == 0 {
+            return Ok(false);
+        }
+        self.live_checks.store(left - 1, Ordering::SeqCst);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+/// 本地的直播流替身：前 connections 次连接返回 body_size 字节后断开，之后返回 404；
+/// truncated 时声明的长度比实际多，模拟传输中途断开
+async fn serve_flv(connections: usize, body_size: usize, truncated: bool) -> String {
+    use tokio::io::AsyncReadExt;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut served = 0;
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await;
+            if served < connections {
+                served += 1;
+                let length = if truncated {
+                    format!("Content-Length: {}\r\n", body_size + 1)
+                } else {
+                    String::new()
+                };
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: video/x-flv\r\n{}Connection: close\r\n\r\n",
+                    length
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&vec![b'F'; body_size]).await;
+            } else {
+                let head =
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                let _ = socket.write_all(head.as_bytes()).await;
+            }
+            let _ = socket.shutdown().await;
+        }
+    });
+    format!("http://{}/live_1.flv", addr)
+}
+
+#[cfg(test)]
+fn test_recorder<'a>(dir: &Path, split: SplitRule, ctx: &'a TaskContext) -> Recorder<'a> {
+    Recorder {
+        client: Client::new(),
+        headers: HeaderMap::new(),
+        prefix: dir.join("room").to_string_lossy().into_owned(),
+        split,
+        ctx,
+        files: Vec::new(),
+        hls_seq: None,
+        reconnect_delay: Duration::from_millis(10),
+    }
+}
+
+#[tokio::test]
+async fn test_record_reconnect_and_split() {
+    use crate::control::{Cancelled, TaskControl};
+    use crate::resume::TestDir;
+    use crate::retry::RetryPolicy;
+    let ctx = TaskContext::new(
+        1,
+        TaskControl::new(),
+        None,
+        Vec::new(),
+        RetryPolicy::new(0, None),
+        Default::default(),
+    );
+
+    // 第一次断流时仍在直播，重连后录到第二个文件；第二次断流时已下播
+    let dir = TestDir::new("live_reconnect");
+    let source = LocalSource {
+        url: serve_flv(2, 60_000, false).await,
+        live_checks: 1.into(),
+    };
+    let files = test_recorder(&dir.0, SplitRule::default(), &ctx)
+        .run(&source)
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    for file in &files {
+        assert_eq!(std::fs::metadata(file).unwrap().len(), 60_000);
+    }
+
+    // 每次都录到数据后中途断开，断开次数超过 MAX_RECONNECTS 也继续录制
+    let dir = TestDir::new("live_drops");
+    let connections = MAX_RECONNECTS as usize + 3;
+    let source = LocalSource {
+        url: serve_flv(connections, 1_000, true).await,
+        live_checks: (connections as u32 - 1).into(),
+    };
+    let files = test_recorder(&dir.0, SplitRule::default(), &ctx)
+        .run(&source)
+        .await
+        .unwrap();
+    assert_eq!(files.len(), connections);
+
+    // 连上后没有数据也计入失败，仍在直播时不会无限重连
+    let dir = TestDir::new("live_empty");
+    let source = LocalSource {
+        url: serve_flv(usize::MAX, 0, false).await,
+        live_checks: u32::MAX.into(),
+    };
+    let result = test_recorder(&dir.0, SplitRule::default(), &ctx)
+        .run(&source)
+        .await;
+    assert!(result.is_err());
+    assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 0);
+
+    // 达到大小后重新连接切分到新文件，连接用完后 404 且已下播
+    let dir = TestDir::new("live_split");
+    let source = LocalSource {
+        url: serve_flv(2, 200_000, false).await,
+        live_checks: 0.into(),
+    };
+    let split = SplitRule {
+        max_bytes: Some(50_000),
+        max_duration: None,
+    };
+    let files = test_recorder(&dir.0, split, &ctx)
+        .run(&source)
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    for file in &files {
+        assert!(std::fs::metadata(file).unwrap().len() >= 50_000);
+    }
+
+    // 取消后报告为取消而不是完成
+    ctx.control.cancel(true);
+    let result = test_recorder(&dir.0, SplitRule::default(), &ctx)
+        .run(&source)
+        .await;
+    assert!(result.unwrap_err().is::<Cancelled>());
+}
@@ -0,0 +1,239 @@
+use crate::download::Stream;
+use crate::resolution::{self, Codec, CodecPreference};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// playurl 接口的 data（普通视频、课程）或 result（番剧）
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlayUrl {
+    /// 当前账号可用的清晰度
+    #[serde(default)]
+    pub accept_quality: Vec<i64>,
+    pub dash: Option<Dash>,
+}
+
+/// dash 清单
+#[derive(Debug, Clone, Deserialize)]
+pub struct Dash {
+    /// 接口在没有视频或音频时返回 null
+    pub video: Option<Vec<DashEntry>>,
+    pub audio: Option<Vec<DashEntry>>,
+    pub dolby: Option<Dolby>,
+    pub flac: Option<Flac>,
+}
+
+/// 杜比全景声音轨
+#[derive(Debug, Clone, Deserialize)]
+pub struct Dolby {
+    pub audio: Option<Vec<DashEntry>>,
+}
+
+/// Hi-Res 无损音轨
+#[derive(Debug, Clone, Deserialize)]
+pub struct Flac {
+    pub audio: Option<DashEntry>,
+}
+
+/// dash 中的一路流
+///
+/// 接口同时返回驼峰和下划线两种字段名，两者都读，用到时取不为空的那个
+#[derive(Debug, Clone, Deserialize)]
+pub struct DashEntry {
+    /// 视频为清晰度代码，音频为音质代码
+    pub id: i64,
+    #[serde(default, rename = "baseUrl")]
+    base_url_camel: Option<String>,
+    #[serde(default)]
+    base_url: Option<String>,
+    #[serde(default, rename = "backupUrl")]
+    backup_url_camel: Option<Vec<String>>,
+    #[serde(default)]
+    backup_url: Option<Vec<String>>,
+    #[serde(default)]
+    pub bandwidth: u64,
+    #[serde(default)]
+    pub codecs: String,
+    #[serde(default)]
+    pub codecid: Option<i64>,
+    /// 接口同时返回 `SegmentBase` 和 `segment_base` 两份相同的内容，用 alias 合并会因字段重复报错，只读前者
+    #[serde(default, rename = "SegmentBase")]
+    pub segment_base: Option<SegmentBase>,
+    /// 文件大小，只有部分接口返回
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// fmp4 的初始化段和索引段范围
+#[derive(Debug, Clone, Deserialize)]
+pub struct SegmentBase {
+    #[serde(alias = "Initialization")]
+    pub initialization: String,
+    #[serde(alias = "indexRange")]
+    pub index_range: String,
+}
+
+impl PlayUrl {
+    /// 普通视频和课程的返回在 `data` 中
+    pub fn ugc(json: &Value) -> Result<Self> {
+        Self::parse(&json["data"]).context("Invalid playurl data")
+    }
+
+    /// 番剧的返回在 `result` 中
+    pub fn pgc(json: &Value) -> Result<Self> {
+        Self::parse(&json["result"]).context("Invalid playurl result")
+    }
+
+    fn parse(value: &Value) -> Result<Self> {
+        if !value.is_object() {
+            anyhow::bail!("playurl response has no data");
+        }
+        Ok(Self::deserialize(value)?)
+    }
+
+    /// 选出要合并的音视频流，请求的清晰度不在账号可用范围内时退回最高清晰度
+    pub fn select(
+        &self,
+        qn: i64,
+        codec: &CodecPreference,
+    ) -> Result<(Stream, Stream, i32, &'static str)> {
+        if !self.accept_quality.is_empty() && !self.accept_quality.contains(&qn) {
+            println!(
+                "qn {} not accepted, available: {:?}",
+                qn, self.accept_quality
+            );
+        }
+        self.dash()?.select(qn, codec)
+    }
+
+    /// dash 清单，没有时多半是需要大会员、购买或只有试看
+    pub fn dash(&self) -> Result<&Dash> {
+        self.dash.as_ref().context(
+            "playurl response has no dash manifest (may require VIP, purchase or be preview only)",
+        )
+    }
+}
+
+impl DashEntry {
+    /// 主地址在前，备用地址按顺序在后，去重
+    pub fn urls(&self) -> Result<Vec<String>> {
+        let base = [&self.base_url_camel, &self.base_url].into_iter().flatten();
+        let backup = [&self.backup_url_camel, &self.backup_url]
+            .into_iter()
+            .flatten()
+            .flatten();
+        let mut urls: Vec<String> = Vec::new();
+        for url in base.chain(backup) {
+            if !url.is_empty() && !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        if urls.is_empty() {
+            anyhow::bail!("dash stream {} ({}) has no url", self.id, self.codecs);
+        }
+        Ok(urls)
+    }
+
+    /// 对应的下载流，保存路径由调用方填写
+    pub fn stream(&self) -> Result<Stream> {
+        Ok(Stream {
+            urls: self.urls()?,
+            path: String::new(),
+            size: self.size,
+        })
+    }
+}
+
+impl Dash {
+    pub fn videos(&self) -> &[DashEntry] {
+        self.video.as_deref().unwrap_or_default()
+    }
+
+    pub fn audios(&self) -> &[DashEntry] {
+        self.audio.as_deref().unwrap_or_default()
+    }
+
+    /// 选出要合并的视频和音频流，返回实际清晰度和视频编码
+    pub fn select(
+        &self,
+        qn: i64,
+        codec: &CodecPreference,
+    ) -> Result<(Stream, Stream, i32, &'static str)> {
+        let videos = self.videos();
+        let video = &videos[resolution::pick_video(videos, qn, codec)?];
+        println!("video: {} {}", video.id, video.codecs);
+        if let Some(segment) = &video.segment_base {
+            println!(
+                "segment base: init {} index {}",
+                segment.initialization, segment.index_range
+            );
+        }
+        let audio = self
+            .audios()
+            .iter()
+            .max_by_key(|a| a.bandwidth)
+            .context("dash manifest has no audio stream")?;
+        println!("audio: {}", audio.id);
+        let name = Codec::of(video).map_or("", Codec::name);
+        Ok((video.stream()?, audio.stream()?, video.id as i32, name))
+    }
+}
+
+#[test]
+fn test_parse_playurl() {
+    let json = serde_json::json!({ "code": 0, "data": {
+        "quality": 80, "accept_quality": [120, 80, 64],
+        "dash": {
+            "duration": 120,
+            "video": [{
+                "id": 80, "baseUrl": "https://a/v.m4s", "base_url": "https://a/v.m4s",
+                "backupUrl": ["https://b/v.m4s"], "backup_url": ["https://b/v.m4s"],
+                "bandwidth": 1000, "codecs": "avc1.640032", "codecid": 7,
+                "SegmentBase": { "Initialization": "0-900", "indexRange": "901-1200" },
+                "segment_base": { "initialization": "0-900", "index_range": "901-1200" },
+            }],
+            "audio": [{ "id": 30280, "base_url": "https://a/a.m4s", "backup_url": null, "bandwidth": 190000, "codecs": "mp4a.40.2" }],
+            "dolby": { "type": 0, "audio": null },
+            "flac": null,
+        },
+    }});
+    let play_url = PlayUrl::ugc(&json).unwrap();
+    assert_eq!(play_url.accept_quality, vec![120, 80, 64]);
+    let dash = play_url.dash().unwrap();
+    let video = &dash.videos()[0];
+    assert_eq!(
+        video.urls().unwrap(),
+        vec!["https://a/v.m4s", "https://b/v.m4s"]
+    );
+    let segment = video.segment_base.as_ref().unwrap();
+    assert_eq!(
+        (
+            segment.initialization.as_str(),
+            segment.index_range.as_str()
+        ),
+        ("0-900", "901-1200")
+    );
+    let (video, audio, qn, codec) = dash.select(80, &CodecPreference::default()).unwrap();
+    assert_eq!((qn, codec), (80, "AVC"));
+    assert_eq!(video.urls.len(), 2);
+    assert_eq!(audio.urls, vec!["https://a/a.m4s"]);
+
+    // 缺少地址、缺少 dash、字段类型不对时给出明确的错误
+    let no_url = serde_json::json!({ "data": { "dash": {
+        "video": [{ "id": 80, "bandwidth": 1 }], "audio": [{ "id": 30280, "base_url": "https://a/a.m4s" }],
+    }}});
+    let err = PlayUrl::ugc(&no_url)
+        .unwrap()
+        .dash()
+        .unwrap()
+        .select(80, &CodecPreference::default());
+    assert!(format!("{:#}", err.unwrap_err()).contains("has no url"));
+    assert!(
+        PlayUrl::pgc(&serde_json::json!({ "result": { "durl": [] } }))
+            .unwrap()
+            .dash()
+            .is_err()
+    );
+    let bad = serde_json::json!({ "data": { "dash": { "video": [{ "id": "80" }] } } });
+    assert!(format!("{:#}", PlayUrl::ugc(&bad).unwrap_err()).contains("invalid type"));
+}
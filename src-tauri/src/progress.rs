@@ -17,3 +17,17 @@ pub struct DownloadProgress {
     /// 当前任务总文件数（如 2=视频+音频）
     pub file_count: u32,
 }
+
+/// 临时错误后的一次重试
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryEvent {
+    /// 正在重试的步骤
+    pub what: String,
+    /// 第几次重试，从 1 开始
+    pub attempt: u32,
+    pub max_retries: u32,
+    /// 重试前的等待毫秒数
+    pub delay_ms: u64,
+    /// 上一次失败的原因
+    pub error: String,
+}
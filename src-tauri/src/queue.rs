@@ -0,0 +1,488 @@
+use crate::config::ConfigState;
+use crate::control::TaskControl;
+use crate::download::TaskContext;
+use crate::progress;
+use crate::ratelimit::SpeedLimitState;
+use crate::resolution::CodecPreference;
+use crate::retry::RetryPolicy;
+use crate::DownloadResult;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{mpsc, Notify};
+
+const QUEUE_FILE: &str = "queue.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus {
+    Pending,
+    Running,
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl TaskStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Done | TaskStatus::Failed | TaskStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadTask {
+    pub id: u64,
+    pub url: String,
+    pub resolution: String,
+    pub save_path: String,
+    pub status: TaskStatus,
+    pub title: Option<String>,
+    pub message: String,
+    /// 下载各个流最终使用的 CDN 主机
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// 批量下载中被跳过的条目
+    #[serde(default)]
+    pub skipped: Vec<String>,
+    /// 实际下载的视频编码
+    #[serde(default)]
+    pub codecs: Vec<String>,
+    /// 所属批量下载中的序号，仅本次运行内有效，不保存
+    #[serde(skip)]
+    pub url_index: Option<usize>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueData {
+    next_id: u64,
+    tasks: Vec<DownloadTask>,
+}
+
+/// 下载队列，任务状态保存在 queue.json，重启后未完成的任务会恢复，已完成的任务会清除
+pub struct QueueState {
+    data: Mutex<QueueData>,
+    /// 已启动（运行或暂停中）任务的控制开关
+    controls: Mutex<HashMap<u64, TaskControl>>,
+    changed: Notify,
+}
+
+impl QueueState {
+    pub fn new() -> Self {
+        let mut data = Self::load().unwrap_or_default();
+        // 上次运行中已完成的任务不再保留，失败和取消的留着以便查看原因
+        data.tasks.retain(|task| task.status != TaskStatus::Done);
+        // 上次退出时正在下载的任务重新排队
+        for task in data.tasks.iter_mut() {
+            if task.status == TaskStatus::Running {
+                task.status = TaskStatus::Pending;
+            }
+        }
+        Self {
+            data: Mutex::new(data),
+            controls: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn load() -> Option<QueueData> {
+        let path = Path::new(QUEUE_FILE);
+        if path.exists() {
+            let content = fs::read_to_string(path).ok()?;
+            serde_json::from_str(&content).ok()
+        } else {
+            None
+        }
+    }
+
+    fn save(data: &QueueData) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(data).map_err(|e| e.to_string())?;
+        fs::write(QUEUE_FILE, json).map_err(|e| e.to_string())
+    }
+
+    /// 修改队列后保存并通知等待者
+    fn update<T>(&self, f: impl FnOnce(&mut QueueData) -> Result<T, String>) -> Result<T, String> {
+        let result = {
+            let mut data = self.data.lock().map_err(|e| e.to_string())?;
+            let result = f(&mut data)?;
+            Self::save(&data)?;
+            result
+        };
+        self.changed.notify_waiters();
+        Ok(result)
+    }
+
+    pub fn list(&self) -> Result<Vec<DownloadTask>, String> {
+        let data = self.data.lock().map_err(|e| e.to_string())?;
+        Ok(data.tasks.clone())
+    }
+
+    /// 添加任务，返回新任务
+    pub fn enqueue(
+        &self,
+        urls: Vec<String>,
+        resolution: &str,
+        save_path: &str,
+    ) -> Result<Vec<DownloadTask>, String> {
+        self.update(|data| {
+            let mut added = Vec::new();
+            for (index, url) in urls.into_iter().enumerate() {
+                data.next_id += 1;
+                let task = DownloadTask {
+                    id: data.next_id,
+                    url,
+                    resolution: resolution.to_string(),
+                    save_path: save_path.to_string(),
+                    status: TaskStatus::Pending,
+                    title: None,
+                    message: String::new(),
+                    hosts: Vec::new(),
+                    skipped: Vec::new(),
+                    codecs: Vec::new(),
+                    url_index: Some(index),
+                };
+                data.tasks.push(task.clone());
+                added.push(task);
+            }
+            Ok(added)
+        })
+    }
+
+    /// 将任务移动到指定位置
+    pub fn move_task(&self, id: u64, index: usize) -> Result<(), String> {
+        self.update(|data| {
+            let from = data
+                .tasks
+                .iter()
+                .position(|t| t.id == id)
+                .ok_or("任务不存在")?;
+            let task = data.tasks.remove(from);
+            let index = index.min(data.tasks.len());
+            data.tasks.insert(index, task);
+            Ok(())
+        })
+    }
+
+    /// 删除未启动的任务，已启动的任务需先取消
+    pub fn remove(&self, id: u64) -> Result<(), String> {
+        self.update(|data| {
+            let index = data
+                .tasks
+                .iter()
+                .position(|t| t.id == id)
+                .ok_or("任务不存在")?;
+            if self.control(id)?.is_some() {
+                return Err("任务正在下载，请先取消".to_string());
+            }
+            data.tasks.remove(index);
+            Ok(())
+        })
+    }
+
+    /// 删除所有已结束（完成、失败、取消）的任务，返回删除的数量
+    pub fn clear_finished(&self) -> Result<usize, String> {
+        self.update(|data| {
+            let controls = self.controls.lock().map_err(|e| e.to_string())?;
+            let before = data.tasks.len();
+            data.tasks
+                .retain(|t| !t.status.is_finished() || controls.contains_key(&t.id));
+            Ok(before - data.tasks.len())
+        })
+    }
+
+    fn control(&self, id: u64) -> Result<Option<TaskControl>, String> {
+        let controls = self.controls.lock().map_err(|e| e.to_string())?;
+        Ok(controls.get(&id).cloned())
+    }
+
+    fn set_status(&self, id: u64, from: &[TaskStatus], to: TaskStatus) -> Result<(), String> {
+        self.update(|data| {
+            let task = data
+                .tasks
+                .iter_mut()
+                .find(|t| t.id == id)
+                .ok_or("任务不存在")?;
+            if !from.contains(&task.status) {
+                return Err("任务当前状态不支持该操作".to_string());
+            }
+            task.status = to;
+            Ok(())
+        })
+    }
+
+    /// 暂停任务，未开始的任务暂停后不会被调度
+    pub fn pause(&self, id: u64) -> Result<(), String> {
+        self.set_status(
+            id,
+            &[TaskStatus::Pending, TaskStatus::Running],
+            TaskStatus::Paused,
+        )?;
+        if let Some(control) = self.control(id)? {
+            control.pause();
+        }
+        Ok(())
+    }
+
+    /// 继续暂停的任务
+    pub fn resume(&self, id: u64) -> Result<(), String> {
+        match self.control(id)? {
+            Some(control) => {
+                self.set_status(id, &[TaskStatus::Paused], TaskStatus::Running)?;
+                control.resume();
+            }
+            None => self.set_status(id, &[TaskStatus::Paused], TaskStatus::Pending)?,
+        }
+        Ok(())
+    }
+
+    /// 取消任务，已启动的任务在下载函数退出后才会标记为已取消
+    pub fn cancel(&self, id: u64, keep_files: bool) -> Result<(), String> {
+        match self.control(id)? {
+            Some(control) => control.cancel(keep_files),
+            None => self.set_status(
+                id,
+                &[TaskStatus::Pending, TaskStatus::Paused],
+                TaskStatus::Cancelled,
+            )?,
+        }
+        Ok(())
+    }
+
+    /// 按队列顺序取出待下载任务，使已启动的任务数不超过 max_running
+    fn start_pending(
+        &self,
+        max_running: usize,
+    ) -> Result<Vec<(DownloadTask, TaskControl)>, String> {
+        self.update(|data| {
+            let mut controls = self.controls.lock().map_err(|e| e.to_string())?;
+            let mut started = Vec::new();
+            for task in data.tasks.iter_mut() {
+                if controls.len() >= max_running {
+                    break;
+                }
+                if task.status == TaskStatus::Pending {
+                    task.status = TaskStatus::Running;
+                    task.message.clear();
+                    task.hosts.clear();
+                    task.skipped.clear();
+                    task.codecs.clear();
+                    let control = TaskControl::new();
+                    controls.insert(task.id, control.clone());
+                    started.push((task.clone(), control));
+                }
+            }
+            Ok(started)
+        })
+    }
+
+    fn set_title(&self, id: u64, title: String) -> Result<(), String> {
+        self.update(|data| {
+            if let Some(task) = data.tasks.iter_mut().find(|t| t.id == id) {
+                task.title = Some(title);
+            }
+            Ok(())
+        })
+    }
+
+    fn finish(&self, id: u64, result: DownloadResult) -> Result<(), String> {
+        self.update(|data| {
+            let control = self.controls.lock().map_err(|e| e.to_string())?.remove(&id);
+            let cancelled = control.is_some_and(|c| c.is_cancelled());
+            if let Some(task) = data.tasks.iter_mut().find(|t| t.id == id) {
+                task.status = if result.success {
+                    TaskStatus::Done
+                } else if cancelled {
+                    TaskStatus::Cancelled
+                } else {
+                    TaskStatus::Failed
+                };
+                task.message = result.message;
+                task.hosts = result.hosts;
+                task.skipped = result.skipped;
+                task.codecs = result.codecs;
+                if result.title.is_some() {
+                    task.title = result.title;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// 等待指定任务全部结束，已被删除的任务视为失败
+    pub async fn wait_all(&self, ids: &[u64]) -> Result<Vec<DownloadResult>, String> {
+        loop {
+            let notified = self.changed.notified();
+            {
+                let data = self.data.lock().map_err(|e| e.to_string())?;
+                let tasks: Vec<Option<&DownloadTask>> = ids
+                    .iter()
+                    .map(|id| data.tasks.iter().find(|t| t.id == *id))
+                    .collect();
+                if tasks.iter().flatten().all(|t| t.status.is_finished()) {
+                    return Ok(tasks
+                        .into_iter()
+                        .map(|task| match task {
+                            Some(task) => DownloadResult {
+                                success: task.status == TaskStatus::Done,
+                                message: task.message.clone(),
+                                title: task.title.clone(),
+                                hosts: task.hosts.clone(),
+                                skipped: task.skipped.clone(),
+                                codecs: task.codecs.clone(),
+                            },
+                            None => DownloadResult {
+                                success: false,
+                                message: "任务已移除".to_string(),
+                                title: None,
+                                hosts: Vec::new(),
+                                skipped: Vec::new(),
+                                codecs: Vec::new(),
+                            },
+                        })
+                        .collect());
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// 启动待下载任务，直到达到并发上限
+pub fn pump(app: &AppHandle) {
+    let max_running = app
+        .state::<ConfigState>()
+        .config
+        .lock()
+        .map(|c| c.max_concurrent.max(1))
+        .unwrap_or(1);
+    let started = match app.state::<QueueState>().start_pending(max_running) {
+        Ok(started) => started,
+        Err(e) => {
+            eprintln!("Failed to start queued tasks: {}", e);
+            return;
+        }
+    };
+    if !started.is_empty() {
+        emit_changed(app);
+    }
+    for (task, control) in started {
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            run_task(app, task, control).await;
+        });
+    }
+}
+
+fn emit_changed(app: &AppHandle) {
+    if let Ok(tasks) = app.state::<QueueState>().list() {
+        let _ = app.emit("download-queue-changed", tasks);
+    }
+}
+
+/// 执行单个队列任务，结束后继续调度下一个
+async fn run_task(app: AppHandle, task: DownloadTask, control: TaskControl) {
+    let (connections, max_retries, codec) = app
+        .state::<ConfigState>()
+        .config
+        .lock()
+        .map(|c| {
+            let codec = CodecPreference {
+                order: c.codec_preference.clone(),
+                strict: c.codec_strict,
+            };
+            (c.connections, c.max_retries, codec)
+        })
+        .unwrap_or((1, 0, CodecPreference::default()));
+    let limiters = app.state::<SpeedLimitState>().task_limiters();
+
+    let (tx, mut rx) = mpsc::channel::<progress::DownloadProgress>(64);
+    let (title_tx, mut title_rx) = mpsc::channel::<(usize, String)>(8);
+    let app_emit = app.clone();
+    let (task_id, url_index) = (task.id, task.url_index);
+    let recv_handle = tokio::spawn(async move {
+        while let Some(p) = rx.recv().await {
+            let payload = serde_json::json!({
+                "task_id": task_id,
+                "url_index": url_index,
+                "downloaded": p.downloaded,
+                "total": p.total,
+                "percent": p.percent,
+                "speed": p.speed,
+                "eta_secs": p.eta_secs,
+                "file_index": p.file_index,
+                "file_count": p.file_count,
+            });
+            let _ = app_emit.emit("download-queue-progress", &payload);
+            if url_index.is_some() {
+                let _ = app_emit.emit("download-progress", payload);
+            }
+        }
+    });
+    let (retry_tx, mut retry_rx) = mpsc::channel::<progress::RetryEvent>(8);
+    let app_retry = app.clone();
+    let retry_handle = tokio::spawn(async move {
+        while let Some(r) = retry_rx.recv().await {
+            let payload = serde_json::json!({
+                "task_id": task_id,
+                "url_index": url_index,
+                "what": r.what,
+                "attempt": r.attempt,
+                "max_retries": r.max_retries,
+                "delay_ms": r.delay_ms,
+                "error": r.error,
+            });
+            let _ = app_retry.emit("download-retry", payload);
+        }
+    });
+    let app_title = app.clone();
+    let title_handle = tokio::spawn(async move {
+        while let Some((_, title)) = title_rx.recv().await {
+            let _ = app_title
+                .state::<QueueState>()
+                .set_title(task_id, title.clone());
+            if let Some(url_index) = url_index {
+                let payload = serde_json::json!({ "url_index": url_index, "title": title });
+                let _ = app_title.emit("download-task-title", payload);
+            }
+        }
+    });
+
+    let result = crate::download_video_with_tx(
+        task.url,
+        task.resolution,
+        task.save_path,
+        &TaskContext::new(
+            connections,
+            control,
+            Some(tx),
+            limiters,
+            RetryPolicy::new(max_retries, Some(retry_tx)),
+            codec,
+        ),
+        Some((url_index.unwrap_or(0), title_tx)),
+    )
+    .await
+    .unwrap_or_else(|e| DownloadResult {
+        success: false,
+        message: e,
+        title: None,
+        hosts: Vec::new(),
+        skipped: Vec::new(),
+        codecs: Vec::new(),
+    });
+    recv_handle.await.ok();
+    title_handle.await.ok();
+    retry_handle.await.ok();
+
+    if let Err(e) = app.state::<QueueState>().finish(task_id, result) {
+        eprintln!("Failed to update task {}: {}", task_id, e);
+    }
+    emit_changed(&app);
+    pump(&app);
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// 令牌桶限速器，速率为 0 表示不限速，可在下载过程中修改
+pub struct RateLimiter {
+    /// 每秒字节数
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// 可用字节数，为负时表示透支，需要等待补足
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// 取走 n 个字节的令牌，不足时先扣成透支再等待补足
+    pub async fn acquire(&self, n: u64) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return;
+        }
+        let wait = {
+            let Ok(mut bucket) = self.bucket.lock() else {
+                return;
+            };
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            // 最多积攒一秒的令牌，避免空闲后突发
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+            bucket.last = now;
+            bucket.tokens -= n as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 全局限速与单任务限速，单位 KiB/s，修改后立即作用于正在下载的任务
+pub struct SpeedLimitState {
+    global: Arc<RateLimiter>,
+    task_rate: AtomicU64,
+    /// 正在下载的任务的限速器，任务结束后自动失效
+    tasks: Mutex<Vec<Weak<RateLimiter>>>,
+}
+
+impl SpeedLimitState {
+    pub fn new(global_kib: u64, task_kib: u64) -> Self {
+        Self {
+            global: Arc::new(RateLimiter::new(global_kib * 1024)),
+            task_rate: AtomicU64::new(task_kib * 1024),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_global(&self, kib: u64) {
+        self.global.set_rate(kib * 1024);
+    }
+
+    pub fn set_per_task(&self, kib: u64) {
+        let rate = kib * 1024;
+        self.task_rate.store(rate, Ordering::Relaxed);
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.retain(|t| match t.upgrade() {
+                Some(limiter) => {
+                    limiter.set_rate(rate);
+                    true
+                }
+                None => false,
+            });
+        }
+    }
+
+    /// 为新任务创建限速器，返回全局和本任务两级
+    pub fn task_limiters(&self) -> Vec<Arc<RateLimiter>> {
+        let task = Arc::new(RateLimiter::new(self.task_rate.load(Ordering::Relaxed)));
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.retain(|t| t.strong_count() > 0);
+            tasks.push(Arc::downgrade(&task));
+        }
+        vec![self.global.clone(), task]
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::new(1024 * 1024);
+    let start = Instant::now();
+    for _ in 0..3 {
+        limiter.acquire(200 * 1024).await;
+    }
+    assert!(start.elapsed() >= Duration::from_millis(500));
+
+    limiter.set_rate(0);
+    let start = Instant::now();
+    limiter.acquire(100 * 1024 * 1024).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
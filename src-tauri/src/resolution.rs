@@ -1,47 +1,329 @@
-use std::collections::HashMap;
-
-pub fn qn(s: &str) -> &str {
-    let hash: HashMap<&str, &str> = [
-        ("HDR", "125"),
-        ("4K", "120"),
-        ("1080P+", "112"),
-        ("1080P60", "116"),
-        ("1080P", "80"),
-        ("720P", "64"),
-        ("480P", "32"),
-        ("360P", "16"),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    hash.get(s).map(|&v| v).unwrap_or("")
-}
-
-pub fn fnval(s: &str) -> &str {
-    let hash: HashMap<&str, &str> = [("HDR", "80"), ("4K", "144")].iter().cloned().collect();
-    hash.get(s).map(|&v| v).unwrap_or("16")
-}
-
-pub fn rsl(s: &str) -> &str {
-    let hash: HashMap<&str, &str> = [
-        ("125", "HDR"),
-        ("120", "4K"),
-        ("112", "1080P+"),
-        ("116", "1080P60"),
-        ("80", "1080P"),
-        ("64", "720P"),
-        ("32", "480P"),
-        ("16", "360P"),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-    hash.get(s).map(|&v| v).unwrap_or("")
+use crate::playurl::DashEntry;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 仅下载音轨的模式，和清晰度放在同一个选项里
+pub const AUDIO_ONLY: &str = "AUDIO";
+
+pub fn is_audio_only(s: &str) -> bool {
+    s == AUDIO_ONLY
+}
+
+/// fnval 中各功能的标记位
+const FNVAL_DASH: u32 = 16;
+const FNVAL_HDR: u32 = 64;
+const FNVAL_4K: u32 = 128;
+const FNVAL_DOLBY_AUDIO: u32 = 256;
+const FNVAL_DOLBY_VISION: u32 = 512;
+const FNVAL_8K: u32 = 1024;
+
+/// 视频清晰度，按画质从低到高排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Quality {
+    P240,
+    P360,
+    P480,
+    P720,
+    P720F60,
+    P1080,
+    P1080Plus,
+    P1080F60,
+    #[default]
+    K4,
+    Hdr,
+    DolbyVision,
+    K8,
+}
+
+impl Quality {
+    /// 从低到高的全部清晰度
+    pub const ALL: [Quality; 12] = [
+        Quality::P240,
+        Quality::P360,
+        Quality::P480,
+        Quality::P720,
+        Quality::P720F60,
+        Quality::P1080,
+        Quality::P1080Plus,
+        Quality::P1080F60,
+        Quality::K4,
+        Quality::Hdr,
+        Quality::DolbyVision,
+        Quality::K8,
+    ];
+
+    /// 接口中的清晰度代码
+    pub fn qn(self) -> i64 {
+        match self {
+            Quality::P240 => 6,
+            Quality::P360 => 16,
+            Quality::P480 => 32,
+            Quality::P720 => 64,
+            Quality::P720F60 => 74,
+            Quality::P1080 => 80,
+            Quality::P1080Plus => 112,
+            Quality::P1080F60 => 116,
+            Quality::K4 => 120,
+            Quality::Hdr => 125,
+            Quality::DolbyVision => 126,
+            Quality::K8 => 127,
+        }
+    }
+
+    pub fn from_qn(qn: i64) -> Option<Quality> {
+        Quality::ALL.into_iter().find(|q| q.qn() == qn)
+    }
+
+    /// 显示和写进文件名的名称
+    pub fn name(self) -> &'static str {
+        match self {
+            Quality::P240 => "240P",
+            Quality::P360 => "360P",
+            Quality::P480 => "480P",
+            Quality::P720 => "720P",
+            Quality::P720F60 => "720P60",
+            Quality::P1080 => "1080P",
+            Quality::P1080Plus => "1080P+",
+            Quality::P1080F60 => "1080P60",
+            Quality::K4 => "4K",
+            Quality::Hdr => "HDR",
+            Quality::DolbyVision => "杜比视界",
+            Quality::K8 => "8K",
+        }
+    }
+
+    /// 只有这个清晰度才需要的 fnval 标记
+    fn fnval_flag(self) -> u32 {
+        match self {
+            Quality::K4 => FNVAL_4K,
+            Quality::Hdr => FNVAL_HDR,
+            Quality::DolbyVision => FNVAL_DOLBY_VISION,
+            Quality::K8 => FNVAL_8K,
+            _ => 0,
+        }
+    }
+
+    /// 请求这个清晰度时 fnval 需要的标记，包含所有不高于它的清晰度的标记，
+    /// 这样没有这个清晰度时接口仍会返回次一级的高清晰度
+    pub fn fnval(self) -> u32 {
+        Quality::ALL
+            .into_iter()
+            .filter(|q| *q <= self)
+            .fold(FNVAL_DASH, |fnval, q| fnval | q.fnval_flag())
+    }
+
+    /// 宽松解析用户输入：名称不区分大小写和空格，也接受 qn 代码和常见别名，无法识别时返回 None
+    pub fn parse(s: &str) -> Option<Quality> {
+        let s: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_uppercase();
+        if let Some(quality) = Quality::ALL.into_iter().find(|q| q.name() == s) {
+            return Some(quality);
+        }
+        let quality = match s.as_str() {
+            "8K超高清" | "4320P" => Quality::K8,
+            "DOLBY" | "DOLBYVISION" | "DV" => Quality::DolbyVision,
+            "HDR真彩" | "HDR真彩色" => Quality::Hdr,
+            "4K超清" | "2160P" => Quality::K4,
+            "1080P60帧" | "1080P高帧率" => Quality::P1080F60,
+            "1080P高码率" | "1080PPLUS" => Quality::P1080Plus,
+            "1080" | "1080P高清" => Quality::P1080,
+            "720P60帧" | "720P高帧率" => Quality::P720F60,
+            "720" | "720P准高清" => Quality::P720,
+            "480" | "480P清晰" => Quality::P480,
+            "360" | "360P流畅" => Quality::P360,
+            "240" | "240P极速" => Quality::P240,
+            _ => return s.parse().ok().and_then(Quality::from_qn),
+        };
+        Some(quality)
+    }
+
+    /// 用户选择的清晰度，无法识别时使用默认的 4K
+    pub fn requested(s: &str) -> Quality {
+        Quality::parse(s).unwrap_or_else(|| {
+            println!("unknown resolution {:?}, using {}", s, Quality::default());
+            Quality::default()
+        })
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 实际下载到的清晰度名称，未知的代码原样显示
+pub fn quality_name(qn: i64) -> String {
+    Quality::from_qn(qn).map_or_else(|| qn.to_string(), |q| q.to_string())
+}
+
+/// playurl 请求的 qn 和 fnval 参数
+pub fn playurl_params(rsl: &str) -> (String, String) {
+    if is_audio_only(rsl) {
+        // 仅音频模式需要带上杜比音频标记，Hi-Res 无损随 dash 一起返回
+        return (String::new(), (FNVAL_DASH | FNVAL_DOLBY_AUDIO).to_string());
+    }
+    let quality = Quality::requested(rsl);
+    (quality.qn().to_string(), quality.fnval().to_string())
+}
+
+/// dash 视频流的编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Avc,
+    Hevc,
+    Av1,
+}
+
+impl Codec {
+    /// 按 dash 条目的 codecid 判断，没有时看 codecs 字符串
+    pub fn of(entry: &DashEntry) -> Option<Codec> {
+        match entry.codecid {
+            Some(7) => return Some(Codec::Avc),
+            Some(12) => return Some(Codec::Hevc),
+            Some(13) => return Some(Codec::Av1),
+            _ => {}
+        }
+        let family = entry.codecs.split('.').next().unwrap_or("");
+        match family {
+            "avc1" | "avc3" => Some(Codec::Avc),
+            "hev1" | "hvc1" => Some(Codec::Hevc),
+            "av01" => Some(Codec::Av1),
+            _ => None,
+        }
+    }
+
+    /// 写进文件名和结果的名称
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Avc => "AVC",
+            Codec::Hevc => "HEVC",
+            Codec::Av1 => "AV1",
+        }
+    }
+}
+
+/// 视频编码偏好，来自配置
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CodecPreference {
+    /// 按优先级排列，空为不限编码（取码率最高的）
+    pub order: Vec<Codec>,
+    /// 没有偏好中的编码时报错，而不是退回其他编码
+    pub strict: bool,
+}
+
+/// 从 dash 视频列表中选出要下载的条目：先按清晰度，再按编码偏好，最后取码率最高的
+///
+/// 没有请求的清晰度时使用不高于它的最高清晰度，都比它高时使用最低的清晰度
+pub fn pick_video(videos: &[DashEntry], qn: i64, pref: &CodecPreference) -> Result<usize> {
+    let qn = videos
+        .iter()
+        .map(|v| v.id)
+        .filter(|id| *id <= qn)
+        .max()
+        .or_else(|| videos.iter().map(|v| v.id).min())
+        .context("No video streams found")?;
+    let best = |codec: Option<Codec>| {
+        videos
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.id == qn)
+            .filter(|(_, v)| codec.is_none() || Codec::of(v) == codec)
+            .max_by_key(|(_, v)| v.bandwidth)
+            .map(|(index, _)| index)
+    };
+    if let Some(index) = pref.order.iter().find_map(|c| best(Some(*c))) {
+        return Ok(index);
+    }
+    if pref.strict && !pref.order.is_empty() {
+        let available: Vec<&str> = videos
+            .iter()
+            .filter(|v| v.id == qn)
+            .filter_map(|v| Codec::of(v).map(Codec::name))
+            .collect();
+        let wanted: Vec<&str> = pref.order.iter().map(|c| c.name()).collect();
+        anyhow::bail!(
+            "没有 {} 编码的视频流，可用编码: {}",
+            wanted.join("/"),
+            available.join("/")
+        );
+    }
+    best(None).context("No video streams found")
+}
+
+#[test]
+fn test_pick_video() {
+    let videos = serde_json::json!([
+        { "id": 80, "codecid": 13, "codecs": "av01.0.08M.08", "bandwidth": 900 },
+        { "id": 80, "codecid": 12, "codecs": "hev1.1.6.L120.90", "bandwidth": 800 },
+        { "id": 80, "codecid": 7, "codecs": "avc1.640032", "bandwidth": 1000 },
+        { "id": 64, "codecs": "avc1.640028", "bandwidth": 500 },
+        { "id": 64, "codecs": "hev1.1.6.L120.90", "bandwidth": 400 },
+    ]);
+    let videos: Vec<DashEntry> = serde_json::from_value(videos).unwrap();
+    let videos = videos.as_slice();
+    let pref = |order: Vec<Codec>, strict| CodecPreference { order, strict };
+    assert_eq!(
+        pick_video(videos, 80, &CodecPreference::default()).unwrap(),
+        2
+    );
+    assert_eq!(
+        pick_video(videos, 80, &pref(vec![Codec::Hevc, Codec::Avc], false)).unwrap(),
+        1
+    );
+    assert_eq!(Codec::of(&videos[4]), Some(Codec::Hevc));
+    // 请求的清晰度不存在时用不高于它的最高清晰度
+    assert_eq!(
+        pick_video(videos, 116, &pref(vec![Codec::Av1], true)).unwrap(),
+        0
+    );
+    assert_eq!(
+        pick_video(videos, 74, &CodecPreference::default()).unwrap(),
+        3
+    );
+    // 都比请求的清晰度高时用最低的清晰度
+    assert_eq!(
+        pick_video(videos, 16, &CodecPreference::default()).unwrap(),
+        3
+    );
+    assert!(pick_video(videos, 64, &pref(vec![Codec::Av1], true)).is_err());
+    assert_eq!(
+        pick_video(videos, 64, &pref(vec![Codec::Av1], false)).unwrap(),
+        3
+    );
 }
 
 #[test]
-fn x() {
-    let s = "HDR";
-    println!("{}", qn(s));
-    println!("{}", fnval(s));
+fn test_quality() {
+    assert!(Quality::K8 > Quality::DolbyVision && Quality::P720F60 > Quality::P720);
+    assert_eq!(Quality::parse(" 1080p60 "), Some(Quality::P1080F60));
+    assert_eq!(Quality::parse("1080P+"), Some(Quality::P1080Plus));
+    assert_eq!(Quality::parse("杜比视界"), Some(Quality::DolbyVision));
+    assert_eq!(Quality::parse("dolby vision"), Some(Quality::DolbyVision));
+    assert_eq!(Quality::parse("127"), Some(Quality::K8));
+    assert_eq!(Quality::parse("720"), Some(Quality::P720));
+    assert_eq!(Quality::parse("6"), Some(Quality::P240));
+    assert_eq!(Quality::parse("99"), None);
+    assert_eq!(Quality::parse(""), None);
+    assert_eq!(Quality::requested("蓝光"), Quality::K4);
+    assert_eq!(Quality::Hdr.fnval(), 208);
+    assert_eq!(Quality::K4.fnval(), 144);
+    assert_eq!(Quality::DolbyVision.fnval(), 720);
+    assert_eq!(Quality::K8.fnval(), 16 | 64 | 128 | 512 | 1024);
+    assert_eq!(Quality::P1080.fnval(), 16);
+    assert_eq!(quality_name(74), "720P60");
+    assert_eq!(quality_name(1), "1");
+    assert_eq!(
+        playurl_params("HDR"),
+        ("125".to_string(), "208".to_string())
+    );
+    assert_eq!(
+        playurl_params(AUDIO_ONLY),
+        (String::new(), "272".to_string())
+    );
 }
@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// 断点续传状态，保存在 `{path}.part.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartState {
+    /// 去掉主机和查询参数的地址，用于判断是否为同一个流
+    pub url_key: String,
+    /// 文件总字节数
+    pub total: u64,
+    /// 已写入 `.part` 的字节数
+    pub downloaded: u64,
+    /// 分段下载时各段的进度，单连接下载时为空
+    #[serde(default)]
+    pub segments: Vec<Segment>,
+}
+
+/// 分段下载中的一段，`[start, end)`，`pos` 为下一个要写入的位置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub pos: u64,
+}
+
+impl Segment {
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.end
+    }
+}
+
+/// 下载中的临时文件路径
+pub fn part_path(path: &str) -> String {
+    format!("{}.part", path)
+}
+
+/// 合并输出的临时文件路径，与目标文件在同一目录，完成后改名
+pub fn temp_output_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+/// 续传状态文件路径
+pub fn state_path(path: &str) -> String {
+    format!("{}.part.json", path)
+}
+
+/// 流地址的查询参数带有过期时间等信息，每次获取都会变化；
+/// 备用 CDN 与主地址路径相同、主机不同，因此只比较路径部分
+pub fn url_key(url: &str) -> String {
+    let url = url.split('?').next().unwrap_or(url);
+    let path = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    match path.find('/') {
+        Some(i) => path[i..].to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// 目标文件已存在即视为下载完成（只有完整下载后才会从 `.part` 改名）
+pub fn is_finished(path: &str) -> bool {
+    Path::new(path).exists()
+}
+
+/// 读取可续传的状态，状态不匹配或文件损坏时清理旧文件并返回 None
+pub fn load(path: &str, url: &str) -> Option<PartState> {
+    let part = part_path(path);
+    let state = fs::read_to_string(state_path(path))
+        .ok()
+        .and_then(|s| serde_json::from_str::<PartState>(&s).ok());
+    let part_len = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    match state {
+        // 分段下载的文件已预分配，长度应等于总大小
+        Some(state)
+            if state.url_key == url_key(url)
+                && !state.segments.is_empty()
+                && part_len == state.total =>
+        {
+            Some(state)
+        }
+        Some(state)
+            if state.url_key == url_key(url)
+                && state.segments.is_empty()
+                && state.downloaded > 0
+                && state.downloaded <= state.total
+                && part_len >= state.downloaded =>
+        {
+            // 状态保存后可能还有未记录的写入，截断到已确认的位置
+            let file = fs::OpenOptions::new().write(true).open(&part).ok()?;
+            file.set_len(state.downloaded).ok()?;
+            Some(state)
+        }
+        _ => {
+            discard(path);
+            None
+        }
+    }
+}
+
+/// 保存续传状态
+pub fn save(path: &str, url: &str, total: u64, downloaded: u64) -> Result<()> {
+    save_segments(path, url, total, downloaded, Vec::new())
+}
+
+/// 保存分段下载的续传状态
+pub fn save_segments(
+    path: &str,
+    url: &str,
+    total: u64,
+    downloaded: u64,
+    segments: Vec<Segment>,
+) -> Result<()> {
+    let state = PartState {
+        url_key: url_key(url),
+        total,
+        downloaded,
+        segments,
+    };
+    let json = serde_json::to_string(&state)?;
+    fs::write(state_path(path), json).context("Failed to save resume state")?;
+    Ok(())
+}
+
+/// 下载完成：`.part` 改名为目标文件并删除状态文件
+pub fn finish(path: &str) -> Result<()> {
+    fs::rename(part_path(path), path).context("Failed to rename part file")?;
+    let _ = fs::remove_file(state_path(path));
+    Ok(())
+}
+
+/// 删除临时文件和状态文件
+pub fn discard(path: &str) {
+    let _ = fs::remove_file(part_path(path));
+    let _ = fs::remove_file(state_path(path));
+}
+
+/// 删除目录（含子目录）下上次运行遗留的合并临时文件，返回删除的个数
+///
+/// 只能在没有任务合并时调用
+pub fn clean_temp_outputs(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            removed += clean_temp_outputs(&path);
+        } else if is_temp_output(&path) && fs::remove_file(&path).is_ok() {
+            println!("removed leftover {}", path.display());
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// 合并或转封装的临时输出：`.mp4.tmp`、`.m4a.tmp`、`.flac.tmp`
+fn is_temp_output(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    [".mp4.tmp", ".m4a.tmp", ".flac.tmp"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// 测试用的临时目录，按进程号和测试名区分，离开作用域时删除
+#[cfg(test)]
+pub struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("bilidown_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_url_key() {
+    let url =
+        "https://upos-sz-mirrorcos.bilivideo.com/upgcxcode/1/2/3-1-30080.m4s?e=abc&deadline=1";
+    assert_eq!(url_key(url), "/upgcxcode/1/2/3-1-30080.m4s");
+    let backup = "https://cn-gdfs-ct-01-01.bilivideo.com/upgcxcode/1/2/3-1-30080.m4s?e=def";
+    assert_eq!(url_key(url), url_key(backup));
+}
+
+#[test]
+fn test_clean_temp_outputs() {
+    let test_dir = TestDir::new("clean_temp");
+    let dir = &test_dir.0;
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let tmp = temp_output_path(&dir.join("sub/a.mp4").to_string_lossy());
+    fs::write(&tmp, b"partial").unwrap();
+    fs::write(dir.join("c.flac.tmp"), b"partial").unwrap();
+    fs::write(dir.join("b.mp4"), b"done").unwrap();
+    assert_eq!(clean_temp_outputs(dir), 2);
+    assert!(!Path::new(&tmp).exists());
+    assert!(dir.join("b.mp4").exists());
+}
@@ -0,0 +1,169 @@
+use crate::control::{Cancelled, Interrupted, TaskControl};
+use crate::download::StreamEnded;
+use crate::progress::RetryEvent;
+use anyhow::Result;
+use rand::Rng;
+use serde_json::Value;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// 第一次重试前的等待时间，之后每次翻倍
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// 单次等待的上限
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// B 站接口返回的非 0 code
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "接口返回错误 {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    /// code 不为 0 时返回 [`ApiError`]
+    pub fn check(json: &Value) -> Result<()> {
+        let code = json["code"].as_i64().unwrap_or(0);
+        if code == 0 {
+            return Ok(());
+        }
+        Err(ApiError {
+            code,
+            // 音频区的接口用 msg
+            message: json["message"]
+                .as_str()
+                .or(json["msg"].as_str())
+                .unwrap_or("")
+                .to_string(),
+        }
+        .into())
+    }
+
+    /// 风控、限流和服务端错误可以重试；-404、地区限制、权限不足等重试也没用
+    pub fn is_transient(&self) -> bool {
+        matches!(self.code, -412 | -352 | -500 | -503 | -509 | -799)
+    }
+}
+
+/// 是否为重试可能成功的临时错误：超时、5xx、连接重置、风控等
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    if e.is::<Interrupted>() || e.is::<Cancelled>() {
+        return false;
+    }
+    e.chain().any(|cause| {
+        if let Some(api) = cause.downcast_ref::<ApiError>() {
+            return api.is_transient();
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => {
+                    status.is_server_error() || status.as_u16() == 412 || status.as_u16() == 429
+                }
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            return matches!(
+                e.kind(),
+                ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut | UnexpectedEof
+            );
+        }
+        cause.is::<tokio::time::error::Elapsed>() || cause.is::<StreamEnded>()
+    })
+}
+
+/// 临时错误的重试策略，每次重试都通过 tx 上报
+#[derive(Clone, Default)]
+pub struct RetryPolicy {
+    /// 最多重试次数，0 为不重试
+    pub max_retries: u32,
+    pub tx: Option<mpsc::Sender<RetryEvent>>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, tx: Option<mpsc::Sender<RetryEvent>>) -> Self {
+        Self { max_retries, tx }
+    }
+
+    /// 执行 f，retryable 判定可重试的错误会退避后重试；等待期间取消会立即返回
+    pub async fn run<T, F, Fut>(
+        &self,
+        what: &str,
+        control: &TaskControl,
+        retryable: fn(&anyhow::Error) -> bool,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let e = match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if attempt >= self.max_retries || !retryable(&e) {
+                return Err(e);
+            }
+            attempt += 1;
+            let delay = backoff_delay(attempt);
+            println!(
+                "{} failed: {:#}, retry {}/{} in {:?}",
+                what, e, attempt, self.max_retries, delay
+            );
+            if let Some(tx) = &self.tx {
+                let _ = tx
+                    .send(RetryEvent {
+                        what: what.to_string(),
+                        attempt,
+                        max_retries: self.max_retries,
+                        delay_ms: delay.as_millis() as u64,
+                        error: format!("{:#}", e),
+                    })
+                    .await;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = control.cancelled() => return Err(Cancelled.into()),
+            }
+        }
+    }
+}
+
+/// 第 attempt 次重试前的等待时间：指数退避，叠加 ±50% 的随机抖动
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let exp = BASE_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_DELAY);
+    exp.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+#[test]
+fn test_is_transient() {
+    let json = serde_json::json!({ "code": -412, "message": "请求被拦截" });
+    assert!(is_transient(&ApiError::check(&json).unwrap_err()));
+    let json = serde_json::json!({ "code": -404, "message": "啥都木有" });
+    assert!(!is_transient(&ApiError::check(&json).unwrap_err()));
+    assert!(ApiError::check(&serde_json::json!({ "code": 0 })).is_ok());
+    assert!(!is_transient(&anyhow::Error::from(Interrupted)));
+    let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+    assert!(is_transient(&anyhow::Error::from(reset).context("read")));
+}
+
+#[test]
+fn test_backoff_delay() {
+    assert!(backoff_delay(1) <= Duration::from_millis(1500));
+    assert!(backoff_delay(3) >= Duration::from_secs(2));
+    assert!(backoff_delay(20) <= MAX_DELAY.mul_f64(1.5));
+}
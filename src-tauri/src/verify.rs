@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fmt;
+use tokio::process::Command;
+
+/// 音视频时长允许的最大差距（秒）
+const DURATION_TOLERANCE: f64 = 2.0;
+
+/// 下载或合并的文件没有通过校验，已删除，需要重新下载
+#[derive(Debug)]
+pub struct VerifyError(pub String);
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "校验失败: {}", self.0)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub fn is_verify_error(e: &anyhow::Error) -> bool {
+    e.is::<VerifyError>()
+}
+
+/// 检查文件大小是否与接口或 Content-Length 给出的一致，不一致时删除文件
+pub fn check_size(path: &str, expected: Option<u64>) -> Result<()> {
+    let Some(expected) = expected.filter(|s| *s > 0) else {
+        return Ok(());
+    };
+    let actual = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path))?
+        .len();
+    if actual != expected {
+        let _ = std::fs::remove_file(path);
+        return Err(VerifyError(format!(
+            "{} 大小为 {} 字节，应为 {} 字节",
+            path, actual, expected
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// 用 ffprobe 检查合并后的 mp4：需要一路视频一路音频，且两者时长接近
+///
+/// 找不到 ffprobe 时跳过检查
+pub async fn probe_mp4(path: &str) -> Result<()> {
+    match ffprobe(path).await? {
+        Some(json) => {
+            check_probe(&json).map_err(|e| VerifyError(format!("{}: {}", path, e)).into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// 用 ffprobe 检查仅音频模式的输出：只有一路有效时长的音频
+pub async fn probe_audio(path: &str) -> Result<()> {
+    match ffprobe(path).await? {
+        Some(json) => {
+            check_audio_probe(&json).map_err(|e| VerifyError(format!("{}: {}", path, e)).into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// 读取各路流的类型和时长，找不到 ffprobe 时返回 None
+async fn ffprobe(path: &str) -> Result<Option<Value>> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "stream=codec_type,duration:format=duration",
+            "-of",
+            "json",
+            path,
+        ])
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    let output = match output {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("ffprobe not found, skip verifying {}", path);
+            return Ok(None);
+        }
+        Err(e) => return Err(e).context("Failed to execute ffprobe"),
+    };
+    if !output.status.success() {
+        return Err(VerifyError(format!(
+            "ffprobe 无法读取 {}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    let json = serde_json::from_slice(&output.stdout).context("Failed to parse ffprobe output")?;
+    Ok(Some(json))
+}
+
+/// 某类流恰好一路时返回其时长
+fn stream_duration(json: &Value, kind: &str) -> std::result::Result<f64, String> {
+    let found: Vec<&Value> = json["streams"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|s| s["codec_type"] == kind)
+        .collect();
+    if found.len() != 1 {
+        return Err(format!("{} 路 {} 流，应为 1 路", found.len(), kind));
+    }
+    // flac 等格式的流没有单独的时长，退回到容器时长
+    [&found[0]["duration"], &json["format"]["duration"]]
+        .iter()
+        .filter_map(|d| d.as_str().and_then(|d| d.parse::<f64>().ok()))
+        .find(|d| *d > 0.0)
+        .ok_or_else(|| format!("{} 流没有有效时长", kind))
+}
+
+/// 检查仅音频文件的 ffprobe 输出
+fn check_audio_probe(json: &Value) -> std::result::Result<(), String> {
+    if json["streams"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|s| s["codec_type"] == "video")
+    {
+        return Err("仅音频文件中含有视频流".to_string());
+    }
+    stream_duration(json, "audio").map(|_| ())
+}
+
+/// 检查 ffprobe 的 json 输出
+fn check_probe(json: &Value) -> std::result::Result<(), String> {
+    let video = stream_duration(json, "video")?;
+    let audio = stream_duration(json, "audio")?;
+    if (video - audio).abs() > DURATION_TOLERANCE {
+        return Err(format!(
+            "视频时长 {:.1}s 与音频时长 {:.1}s 不一致",
+            video, audio
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_probe() {
+    let ok = serde_json::json!({ "streams": [
+        { "codec_type": "video", "duration": "120.000000" },
+        { "codec_type": "audio", "duration": "119.980000" },
+    ]});
+    assert!(check_probe(&ok).is_ok());
+    let short = serde_json::json!({ "streams": [
+        { "codec_type": "video", "duration": "120.000000" },
+        { "codec_type": "audio", "duration": "60.000000" },
+    ]});
+    assert!(check_probe(&short).is_err());
+    let no_audio = serde_json::json!({ "streams": [
+        { "codec_type": "video", "duration": "120.000000" },
+    ]});
+    assert!(check_probe(&no_audio).is_err());
+}
+
+#[test]
+fn test_check_audio_probe() {
+    let flac = serde_json::json!({
+        "streams": [{ "codec_type": "audio" }],
+        "format": { "duration": "240.500000" },
+    });
+    assert!(check_audio_probe(&flac).is_ok());
+    let with_video = serde_json::json!({ "streams": [
+        { "codec_type": "video", "duration": "240.000000" },
+        { "codec_type": "audio", "duration": "240.000000" },
+    ]});
+    assert!(check_audio_probe(&with_video).is_err());
+    let empty =
+        serde_json::json!({ "streams": [{ "codec_type": "audio", "duration": "0.000000" }] });
+    assert!(check_audio_probe(&empty).is_err());
+}
@@ -0,0 +1,146 @@
+use crate::down_bangumi::read_cookie_or_not;
+use crate::refresh_cookie::{create_headers, Cookies};
+use crate::retry::ApiError;
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+/// 失效视频在列表中显示的标题
+const INVALID_TITLE: &str = "已失效视频";
+
+/// 稍后再看中的一个视频
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchLaterItem {
+    pub aid: i64,
+    pub bv_id: String,
+    pub title: String,
+    pub cover: String,
+    /// 时长（秒）
+    pub duration: u64,
+    /// 分P数
+    pub pages: u32,
+    pub owner: String,
+    /// 视频已失效（被删除或不可见）
+    pub invalid: bool,
+}
+
+impl WatchLaterItem {
+    /// 失效视频不下载，记为跳过的原因
+    pub fn skip_reason(&self) -> Option<String> {
+        self.invalid
+            .then(|| format!("{} {}: 已失效", self.bv_id, self.title))
+    }
+
+    /// 交给下载队列的链接，多P视频下载全部分P
+    pub fn url(&self) -> String {
+        if self.pages > 1 {
+            format!("https://www.bilibili.com/video/{}?p=all", self.bv_id)
+        } else {
+            format!("https://www.bilibili.com/video/{}", self.bv_id)
+        }
+    }
+}
+
+/// 读取登录信息，未登录时返回错误
+async fn login_cookies() -> Result<Cookies> {
+    let cookies = read_cookie_or_not(Path::new("load")).await?;
+    if cookies.SESSDATA.is_empty() {
+        return Err(anyhow::anyhow!("Not logged in"));
+    }
+    Ok(cookies)
+}
+
+/// 获取稍后再看列表
+pub async fn list() -> Result<Vec<WatchLaterItem>> {
+    let cookies = login_cookies().await?;
+    let client = Client::new();
+    let json: Value = client
+        .get("https://api.bilibili.com/x/v2/history/toview")
+        .headers(create_headers(&cookies))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Failed to parse watch later list")?;
+    ApiError::check(&json)?;
+    Ok(json["data"]["list"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(parse_item)
+        .collect())
+}
+
+fn parse_item(item: &Value) -> Option<WatchLaterItem> {
+    Some(WatchLaterItem {
+        aid: item["aid"].as_i64()?,
+        bv_id: item["bvid"].as_str()?.to_string(),
+        title: item["title"].as_str().unwrap_or("").to_string(),
+        cover: item["pic"].as_str().unwrap_or("").to_string(),
+        duration: item["duration"].as_u64().unwrap_or(0),
+        pages: item["videos"].as_u64().unwrap_or(1) as u32,
+        owner: item["owner"]["name"].as_str().unwrap_or("").to_string(),
+        // 被删除的视频 state 不一定非 0，但标题会变成“已失效视频”
+        invalid: item["state"].as_i64().unwrap_or(0) != 0
+            || item["title"].as_str() == Some(INVALID_TITLE),
+    })
+}
+
+/// 从稍后再看中移除视频，需要 `bili_jct` 作为 csrf
+pub async fn remove(aids: &[i64]) -> Result<()> {
+    let cookies = login_cookies().await?;
+    if cookies.bili_jct.is_empty() {
+        return Err(anyhow::anyhow!("Missing bili_jct, please log in again"));
+    }
+    let client = Client::new();
+    let headers = csrf_headers(&cookies)?;
+    for aid in aids {
+        let aid = aid.to_string();
+        let json: Value = client
+            .post("https://api.bilibili.com/x/v2/history/toview/del")
+            .headers(headers.clone())
+            .form(&[("aid", aid.as_str()), ("csrf", cookies.bili_jct.as_str())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        ApiError::check(&json).with_context(|| format!("Failed to remove av{}", aid))?;
+    }
+    Ok(())
+}
+
+/// 写操作的 csrf 校验要求 Cookie 中的 bili_jct 与参数一致
+fn csrf_headers(cookies: &Cookies) -> Result<HeaderMap> {
+    let mut headers = create_headers(cookies);
+    headers.insert(
+        "Cookie",
+        HeaderValue::from_str(&format!(
+            "SESSDATA={}; bili_jct={}",
+            cookies.SESSDATA, cookies.bili_jct
+        ))?,
+    );
+    Ok(headers)
+}
+
+#[test]
+fn test_parse_item() {
+    let item = serde_json::json!({
+        "aid": 170001, "bvid": "BV17x411w7KC", "title": "测试", "pic": "http://i0.hdslb.com/a.jpg",
+        "duration": 300, "videos": 3, "owner": { "name": "UP" }, "state": 0,
+    });
+    let item = parse_item(&item).unwrap();
+    assert_eq!(
+        item.url(),
+        "https://www.bilibili.com/video/BV17x411w7KC?p=all"
+    );
+    assert!(!item.invalid && item.skip_reason().is_none());
+    let deleted =
+        serde_json::json!({ "aid": 170002, "bvid": "BV1b", "title": "已失效视频", "state": 0 });
+    let deleted = parse_item(&deleted).unwrap();
+    assert_eq!(deleted.skip_reason().unwrap(), "BV1b 已失效视频: 已失效");
+}
@@ -127,6 +127,12 @@ fn take_filename(url: String) -> Option<String> {
 //     println!("{:?}", x.w_rid);
 // }
 
+/// 对带参数的请求进行 wbi 签名，返回完整的 query（含 wts 和 w_rid）
+pub async fn sign_query(params: Vec<(&str, String)>) -> Result<String, reqwest::Error> {
+    let keys = get_wbi_keys().await?;
+    Ok(encode_wbi(params, keys))
+}
+
 #[derive(Deserialize)]
 pub struct WbiKey {
     pub wts: String,